@@ -1,80 +1,878 @@
-use std::io;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::mem;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-fn at_least_three_vowels(line: &str) -> bool {
-    line.chars().filter(|&c| "aeiou".contains(c)).count() >= 3
+use anyhow::{anyhow, Error, Result};
+use itertools::Itertools;
+use unicode_segmentation::UnicodeSegmentation;
+
+const DEFAULT_RULES: &str = "
+[Part 1]
+at_least_three_vowels: at-least 3 aeiou
+has_repeated_letter: repeat 0
+has_no_special_substrings: forbid ab cd pq xy
+
+[Part 2]
+has_repeated_letter_pair: repeat-ngram 2
+has_repeated_letter_with_one_in_between: repeat 1
+";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Predicate {
+    AtLeast(usize, String),
+    Forbid(Vec<String>),
+    Repeat(usize),
+    RepeatNgram(usize),
+}
+
+use Predicate::*;
+
+impl FromStr for Predicate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words[..] {
+            ["at-least", n, set] => Ok(AtLeast(n.parse()?, set.to_owned())),
+            ["forbid", ref substrings @ ..] if !substrings.is_empty() => {
+                Ok(Forbid(substrings.iter().map(|&s| s.to_owned()).collect()))
+            }
+            ["repeat", gap] => Ok(Repeat(gap.parse()?)),
+            ["repeat-ngram", n] => match n.parse()? {
+                0 => Err(anyhow!("n-gram length must be positive in {s:?}")),
+                n => Ok(RepeatNgram(n)),
+            },
+            _ => Err(anyhow!("Failed to parse predicate {s:?}")),
+        }
+    }
+}
+
+/// How a line is split into the units that the predicates compare.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Byte,
+    Char,
+    Grapheme,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "byte" => Ok(Mode::Byte),
+            "char" => Ok(Mode::Char),
+            "grapheme" => Ok(Mode::Grapheme),
+            _ => Err(anyhow!("Unknown mode {s:?}")),
+        }
+    }
+}
+
+fn segment(s: &str, mode: Mode) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    segment_into(s, mode, &mut units);
+    units
+}
+
+/// Replace the contents of `units` with the segments of `s`, so that one
+/// buffer can be reused across lines.
+fn segment_into<'a>(s: &'a str, mode: Mode, units: &mut Vec<&'a [u8]>) {
+    let bytes = s.as_bytes();
+    units.clear();
+    match mode {
+        Mode::Byte => units.extend(bytes.chunks(1)),
+        Mode::Char => units.extend(s.char_indices().map(|(i, c)| &bytes[i..i + c.len_utf8()])),
+        Mode::Grapheme => units.extend(s.graphemes(true).map(str::as_bytes)),
+    }
+}
+
+fn unit_str(unit: &[u8]) -> String {
+    String::from_utf8_lossy(unit).into_owned()
+}
+
+// The primitives below work on a segmented line, and report positions as
+// unit indices. Lines too short to hold a repeat simply don't have one.
+
+/// Most units are a single byte, so compare those without calling memcmp.
+fn same_unit(a: &[u8], b: &[u8]) -> bool {
+    match (a, b) {
+        ([a], [b]) => a == b,
+        _ => a == b,
+    }
+}
+
+fn same_units(a: &[&[u8]], b: &[&[u8]]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_unit(a, b))
+}
+
+fn is_one_of(unit: &[u8], set: &[Vec<u8>]) -> bool {
+    set.iter().any(|member| same_unit(member, unit))
+}
+
+fn find_all_of(units: &[&[u8]], set: &[Vec<u8>]) -> Vec<usize> {
+    (0..units.len())
+        .filter(|&i| is_one_of(units[i], set))
+        .collect()
+}
+
+fn find_any_of(units: &[&[u8]], substrings: &[Vec<Vec<u8>>]) -> Option<(usize, usize)> {
+    (0..units.len()).find_map(|i| {
+        substrings
+            .iter()
+            .position(|special| {
+                units.len() - i >= special.len()
+                    && special
+                        .iter()
+                        .zip(&units[i..])
+                        .all(|(s, u)| same_unit(s, u))
+            })
+            .map(|which| (i, which))
+    })
+}
+
+fn find_repeat_with_gap(units: &[&[u8]], gap: usize) -> Option<usize> {
+    (gap + 1..units.len()).find(|&i| same_unit(units[i], units[i - gap - 1]))
 }
 
-fn has_repeated_letter(line: &str) -> bool {
-    let mut prev: u8 = 0;
-    for cur in line.bytes() {
-        if cur == prev {
-            return true;
+fn find_repeated_ngram(units: &[&[u8]], n: usize) -> Option<(usize, usize)> {
+    (0..units.len().saturating_sub(2 * n - 1)).find_map(|i| {
+        let ngram = &units[i..i + n];
+        (i + n..=units.len() - n)
+            .find(|&j| same_units(&units[j..j + n], ngram))
+            .map(|j| (i, j))
+    })
+}
+
+impl Predicate {
+    /// Each argument split into units, for a rule's `args`.
+    fn segment_args(&self, mode: Mode) -> Vec<Vec<Vec<u8>>> {
+        let args = match self {
+            AtLeast(_, set) => vec![set],
+            Forbid(substrings) => substrings.iter().collect(),
+            Repeat(_) | RepeatNgram(_) => vec![],
+        };
+        args.into_iter()
+            .map(|arg| segment(arg, mode).into_iter().map(<[u8]>::to_vec).collect())
+            .collect()
+    }
+
+    /// Whether the units of a line pass, given the arguments from
+    /// `segment_args` in the same mode.
+    fn matches(&self, units: &[&[u8]], args: &[Vec<Vec<u8>>]) -> bool {
+        match self {
+            AtLeast(n, _) => {
+                units
+                    .iter()
+                    .filter(|unit| is_one_of(unit, &args[0]))
+                    .count()
+                    >= *n
+            }
+            Forbid(_) => find_any_of(units, args).is_none(),
+            Repeat(gap) => find_repeat_with_gap(units, *gap).is_some(),
+            RepeatNgram(n) => find_repeated_ngram(units, *n).is_some(),
+        }
+    }
+
+    fn explain(&self, units: &[&[u8]], args: &[Vec<Vec<u8>>]) -> (bool, Evidence) {
+        match self {
+            AtLeast(n, _) => {
+                let found: Vec<(usize, String)> = find_all_of(units, &args[0])
+                    .into_iter()
+                    .map(|i| (i, unit_str(units[i])))
+                    .collect();
+                (found.len() >= *n, Evidence::Found(found))
+            }
+            Forbid(substrings) => find_any_of(units, args)
+                .map_or((true, Evidence::Nothing), |(pos, which)| {
+                    (false, Evidence::Substring(substrings[which].clone(), pos))
+                }),
+            Repeat(gap) => {
+                find_repeat_with_gap(units, *gap).map_or((false, Evidence::Nothing), |i| {
+                    let repeated = unit_str(units[i]);
+                    (true, Evidence::Repeat(repeated, i - gap - 1, i))
+                })
+            }
+            RepeatNgram(n) => find_repeated_ngram(units, *n).map_or(
+                (false, Evidence::Nothing),
+                |(first, second)| {
+                    let ngram = unit_str(&units[first..first + n].concat());
+                    (true, Evidence::Repeat(ngram, first, second))
+                },
+            ),
         }
-        prev = cur;
     }
-    false
 }
 
-fn has_no_special_substrings(line: &str) -> bool {
-    for special in ["ab", "cd", "pq", "xy"] {
-        if line.contains(special) {
-            return false;
+/// What a predicate found in a line, explaining why it passed or failed.
+#[derive(Debug)]
+enum Evidence {
+    Nothing,
+    Found(Vec<(usize, String)>),
+    Substring(String, usize),
+    Repeat(String, usize, usize),
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Evidence::Nothing => write!(f, "-"),
+            Evidence::Found(found) => {
+                write!(f, "found {}:", found.len())?;
+                for (pos, c) in found {
+                    write!(f, " {c}@{pos}")?;
+                }
+                Ok(())
+            }
+            Evidence::Substring(s, pos) => write!(f, "{s:?} at {pos}"),
+            Evidence::Repeat(s, first, second) => write!(f, "{s:?} at {first} and {second}"),
         }
     }
-    true
 }
 
-fn has_repeated_letter_pair(line: &str) -> bool {
-    for i in 0..(line.as_bytes().len() - 2) {
-        if line[i + 2..].contains(&line[i..i + 2]) {
-            return true;
+impl Evidence {
+    fn to_json(&self) -> json::JsonValue {
+        match self {
+            Evidence::Nothing => json::Null,
+            Evidence::Found(found) => json::JsonValue::Array(
+                found
+                    .iter()
+                    .map(|(pos, c)| json::object! { "unit": c.as_str(), "pos": *pos })
+                    .collect(),
+            ),
+            Evidence::Substring(s, pos) => json::object! { "substring": s.as_str(), "pos": *pos },
+            Evidence::Repeat(s, first, second) => {
+                json::object! { "repeated": s.as_str(), "pos": [*first, *second] }
+            }
         }
     }
-    false
 }
 
-fn has_repeated_letter_with_one_in_between(line: &str) -> bool {
-    let mut prev1: u8 = 0;
-    let mut prev2: u8 = 0;
-    for cur in line.bytes() {
-        if cur == prev2 {
-            return true;
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Report {
+    Table,
+    Json,
+}
+
+impl FromStr for Report {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Report::Table),
+            "json" => Ok(Report::Json),
+            _ => Err(anyhow!("Unknown report format {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    pred: Predicate,
+    /// The predicate's arguments, segmented once for the whole input.
+    args: Vec<Vec<Vec<u8>>>,
+}
+
+impl Rule {
+    fn matches(&self, units: &[&[u8]]) -> bool {
+        self.pred.matches(units, &self.args)
+    }
+
+    fn explain(&self, units: &[&[u8]]) -> (bool, Evidence) {
+        self.pred.explain(units, &self.args)
+    }
+}
+
+#[derive(Debug)]
+struct RuleSet {
+    name: String,
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    fn is_nice(&self, units: &[&[u8]]) -> bool {
+        self.rules.iter().all(|rule| rule.matches(units))
+    }
+}
+
+/// Print each rule's pass/fail result and evidence for one line.
+fn explain(line: &str, rulesets: &[RuleSet], mode: Mode, report: Report) {
+    let units = segment(line, mode);
+    match report {
+        Report::Table => {
+            println!("{line}");
+            for ruleset in rulesets {
+                let nice = if ruleset.is_nice(&units) {
+                    "nice"
+                } else {
+                    "naughty"
+                };
+                println!("  [{}] {nice}", ruleset.name);
+                for rule in &ruleset.rules {
+                    let (pass, evidence) = rule.explain(&units);
+                    let result = if pass { "PASS" } else { "FAIL" };
+                    println!("    {:<40} {result} {evidence}", rule.name);
+                }
+            }
+        }
+        Report::Json => {
+            let rulesets: Vec<json::JsonValue> = rulesets
+                .iter()
+                .map(|ruleset| {
+                    let rules: Vec<json::JsonValue> = ruleset
+                        .rules
+                        .iter()
+                        .map(|rule| {
+                            let (pass, evidence) = rule.explain(&units);
+                            json::object! {
+                                "rule": rule.name.as_str(),
+                                "pass": pass,
+                                "evidence": evidence.to_json(),
+                            }
+                        })
+                        .collect();
+                    json::object! {
+                        "ruleset": ruleset.name.as_str(),
+                        "nice": ruleset.is_nice(&units),
+                        "rules": rules,
+                    }
+                })
+                .collect();
+            println!("{}", json::object! { "line": line, "rulesets": rulesets });
+        }
+    }
+}
+
+const BATCH_SIZE: usize = 4096;
+
+/// Per-rule hit counts and per-rule-set nice totals.
+#[derive(Clone, Debug)]
+struct Tally {
+    hits: Vec<Vec<usize>>,
+    nice: Vec<usize>,
+}
+
+impl Tally {
+    fn new(rulesets: &[RuleSet]) -> Self {
+        Tally {
+            hits: rulesets
+                .iter()
+                .map(|ruleset| vec![0; ruleset.rules.len()])
+                .collect(),
+            nice: vec![0; rulesets.len()],
+        }
+    }
+
+    fn merge(&mut self, other: &Tally) {
+        for (hits, other_hits) in self.hits.iter_mut().zip(&other.hits) {
+            for (hit, other_hit) in hits.iter_mut().zip(other_hits) {
+                *hit += other_hit;
+            }
+        }
+        for (nice, other_nice) in self.nice.iter_mut().zip(&other.nice) {
+            *nice += other_nice;
         }
-        prev2 = prev1;
-        prev1 = cur;
     }
-    false
 }
 
-fn main() {
-    let part1_predicates = [
-        at_least_three_vowels,
-        has_repeated_letter,
-        has_no_special_substrings,
-    ];
-    let part2_predicates = [
-        has_repeated_letter_pair,
-        has_repeated_letter_with_one_in_between,
-    ];
+/// A classified batch of lines. The lines and their per-rule-set verdicts
+/// are only kept when they are to be written out.
+struct Classified {
+    index: usize,
+    lines: Vec<String>,
+    verdicts: Vec<Vec<bool>>,
+    tally: Tally,
+}
+
+fn classify(
+    index: usize,
+    lines: Vec<String>,
+    rulesets: &[RuleSet],
+    mode: Mode,
+    keep: bool,
+) -> Classified {
+    let mut tally = Tally::new(rulesets);
+    let mut verdicts = Vec::new();
+    let mut units = Vec::new();
+    for line in &lines {
+        segment_into(line, mode, &mut units);
+        let mut line_verdicts = Vec::with_capacity(rulesets.len());
+        for (r, ruleset) in rulesets.iter().enumerate() {
+            let mut nice = true;
+            for (i, rule) in ruleset.rules.iter().enumerate() {
+                if rule.matches(&units) {
+                    tally.hits[r][i] += 1;
+                } else {
+                    nice = false;
+                }
+            }
+            if nice {
+                tally.nice[r] += 1;
+            }
+            line_verdicts.push(nice);
+        }
+        if keep {
+            verdicts.push(line_verdicts);
+        }
+    }
+    let lines = if keep { lines } else { Vec::new() };
+    Classified {
+        index,
+        lines,
+        verdicts,
+        tally,
+    }
+}
 
-    let mut part1 = 0;
-    let mut part2 = 0;
+/// Send stdin to the workers in numbered batches of non-empty lines, then
+/// any read error, which ends the stream.
+fn read_batches(sender: SyncSender<io::Result<(usize, Vec<String>)>>) {
+    let mut stdin = io::stdin().lock();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut index = 0;
+    let mut line = String::new();
     loop {
-        let mut line = String::new();
-        io::stdin()
-            .read_line(&mut line)
-            .expect("Failed to read line");
+        line.clear();
+        match stdin.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return;
+            }
+        }
         let line = line.trim();
         if line.is_empty() {
-            break; // stop on first empty line
+            continue;
+        }
+        batch.push(line.to_owned());
+        if batch.len() == BATCH_SIZE {
+            // The workers are gone if classification was abandoned
+            if sender.send(Ok((index, mem::take(&mut batch)))).is_err() {
+                return;
+            }
+            index += 1;
+        }
+    }
+    if !batch.is_empty() {
+        let _ = sender.send(Ok((index, batch)));
+    }
+}
+
+/// Nice and naughty output files for each rule set.
+type SplitFiles = Vec<(BufWriter<File>, BufWriter<File>)>;
+
+fn open_split_files(dir: &str, rulesets: &[RuleSet]) -> Result<SplitFiles> {
+    fs::create_dir_all(dir)?;
+    rulesets
+        .iter()
+        .map(|ruleset| {
+            let stem: String = ruleset
+                .name
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            let path = Path::new(dir).join(stem);
+            let nice = File::create(path.with_extension("nice"))?;
+            let naughty = File::create(path.with_extension("naughty"))?;
+            Ok((BufWriter::new(nice), BufWriter::new(naughty)))
+        })
+        .collect()
+}
+
+fn write_split(files: &mut SplitFiles, classified: &Classified) -> Result<()> {
+    for (line, verdicts) in classified.lines.iter().zip(&classified.verdicts) {
+        for ((nice, naughty), &verdict) in files.iter_mut().zip(verdicts) {
+            writeln!(if verdict { nice } else { naughty }, "{line}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Classify lines from stdin on all cores, while reading ahead in batches.
+///
+/// Split files receive their lines in input order.
+fn classify_all(rulesets: &[RuleSet], mode: Mode, mut split: Option<SplitFiles>) -> Result<Tally> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let (batch_tx, batch_rx) = mpsc::sync_channel(2 * workers);
+    // Only the workers hold the receiver, so the reader stops once they do
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (result_tx, result_rx) = mpsc::sync_channel(2 * workers);
+    let keep = split.is_some();
+    thread::scope(|s| {
+        s.spawn(move || read_batches(batch_tx));
+        for _ in 0..workers {
+            let batch_rx = Arc::clone(&batch_rx);
+            let result_tx = result_tx.clone();
+            s.spawn(move || loop {
+                let batch = match batch_rx.lock().unwrap().recv() {
+                    Ok(batch) => batch,
+                    Err(_) => break,
+                };
+                let classified =
+                    batch.map(|(index, lines)| classify(index, lines, rulesets, mode, keep));
+                // Results are no longer wanted after an error
+                if result_tx.send(classified).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(batch_rx);
+        drop(result_tx);
+
+        let mut total = Tally::new(rulesets);
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for classified in result_rx {
+            let classified = classified?;
+            total.merge(&classified.tally);
+            if let Some(files) = split.as_mut() {
+                pending.insert(classified.index, classified);
+                while let Some(classified) = pending.remove(&next) {
+                    write_split(files, &classified)?;
+                    next += 1;
+                }
+            }
+        }
+        for (nice, naughty) in split.iter_mut().flatten() {
+            nice.flush()?;
+            naughty.flush()?;
+        }
+        Ok(total)
+    })
+}
+
+/// Small seeded PRNG (SplitMix64), so generated corpora are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, (self.next_u64() % (i as u64 + 1)) as usize);
+        }
+    }
+}
+
+/// Whether `prefix` might still be extended to `len` units on which `rule`
+/// evaluates to `want`. Only prefixes that can never get there are rejected.
+fn can_extend(rule: &Rule, prefix: &[&[u8]], len: usize, want: bool) -> bool {
+    let remaining = len - prefix.len();
+    match (&rule.pred, want) {
+        (AtLeast(n, _), _) => {
+            let found = find_all_of(prefix, &rule.args[0]).len();
+            if want {
+                found + remaining >= *n
+            } else {
+                found < *n
+            }
         }
-        if part1_predicates.iter().all(|pred| pred(line)) {
-            part1 += 1;
+        (Forbid(_), true) | (Repeat(_), false) | (RepeatNgram(_), false) => {
+            rule.matches(prefix) == want
         }
-        if part2_predicates.iter().all(|pred| pred(line)) {
-            part2 += 1;
+        (Forbid(_), false) => !rule.matches(prefix) || remaining > 0,
+        (Repeat(gap), true) => rule.matches(prefix) || (remaining > 0 && len >= gap + 2),
+        (RepeatNgram(n), true) => rule.matches(prefix) || (remaining > 0 && len >= 2 * n),
+    }
+}
+
+/// Outcome of a bounded backtracking search.
+enum Search {
+    Found(String),
+    Exhausted,
+    OutOfSteps,
+}
+
+struct Generator<'a> {
+    constraints: Vec<(&'a Rule, bool)>,
+    alphabet: Vec<&'a str>,
+    len: usize,
+    mode: Mode,
+    steps: usize,
+}
+
+impl Generator<'_> {
+    fn search(&mut self, prefix: &mut String, rng: &mut Rng) -> Search {
+        let units = segment(prefix, self.mode);
+        if units.len() == self.len {
+            return if self
+                .constraints
+                .iter()
+                .all(|(rule, want)| rule.matches(&units) == *want)
+            {
+                Search::Found(prefix.clone())
+            } else {
+                Search::Exhausted
+            };
+        }
+        if !self
+            .constraints
+            .iter()
+            .all(|(rule, want)| can_extend(rule, &units, self.len, *want))
+        {
+            return Search::Exhausted;
+        }
+        let mut alphabet = self.alphabet.clone();
+        rng.shuffle(&mut alphabet);
+        let mut result = Search::Exhausted;
+        for unit in alphabet {
+            if self.steps == 0 {
+                return Search::OutOfSteps;
+            }
+            self.steps -= 1;
+            let before = prefix.len();
+            prefix.push_str(unit);
+            match self.search(prefix, rng) {
+                Search::Found(s) => return Search::Found(s),
+                Search::OutOfSteps => result = Search::OutOfSteps,
+                Search::Exhausted => (),
+            }
+            prefix.truncate(before);
+        }
+        result
+    }
+}
+
+/// Generate up to `count` distinct strings of `len` units that pass or fail
+/// each of the given rules, as requested. There may be fewer when the
+/// search keeps finding the same ones, as happens when few strings qualify.
+fn generate(
+    constraints: &[(&Rule, bool)],
+    alphabet: &str,
+    len: usize,
+    count: usize,
+    mode: Mode,
+    rng: &mut Rng,
+) -> Result<Vec<String>> {
+    const MAX_STEPS: usize = 1_000_000;
+    let mut found: Vec<String> = Vec::new();
+    let mut generator = Generator {
+        constraints: constraints.to_vec(),
+        alphabet: segment(alphabet, mode)
+            .into_iter()
+            .map(|unit| std::str::from_utf8(unit))
+            .collect::<Result<_, _>>()?,
+        len,
+        mode,
+        steps: 0,
+    };
+    for _ in 0..count * 10 {
+        if found.len() == count {
+            break;
+        }
+        generator.steps = MAX_STEPS;
+        match generator.search(&mut String::new(), rng) {
+            Search::Found(s) if !found.contains(&s) => found.push(s),
+            Search::Found(_) => (),
+            Search::Exhausted => {
+                return Err(anyhow!("no string of length {len} satisfies these rules"))
+            }
+            Search::OutOfSteps => {
+                return Err(anyhow!("gave up after {MAX_STEPS} search steps"));
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn find_rule<'a>(rulesets: &'a [RuleSet], name: &str) -> Result<&'a Rule> {
+    rulesets
+        .iter()
+        .flat_map(|ruleset| &ruleset.rules)
+        .find(|rule| rule.name == name)
+        .ok_or_else(|| anyhow!("unknown rule {name:?}"))
+}
+
+/// Parse rule sets from a config with one `[name]` header per rule set,
+/// followed by `rule_name: predicate args...` lines. Arguments are split
+/// into units for `mode`.
+fn parse_rules(config: &str, mode: Mode) -> Result<Vec<RuleSet>> {
+    let mut rulesets: Vec<RuleSet> = Vec::new();
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            rulesets.push(RuleSet {
+                name: name.to_owned(),
+                rules: Vec::new(),
+            });
+            continue;
+        }
+        let (name, pred) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("missing ':' in {line:?}"))?;
+        let name = name.trim();
+        let ruleset = rulesets
+            .last_mut()
+            .ok_or_else(|| anyhow!("rule {name:?} outside of a [rule set]"))?;
+        if ruleset.rules.iter().any(|rule| rule.name == name) {
+            return Err(anyhow!("duplicate rule {name:?} in [{}]", ruleset.name));
+        }
+        let pred: Predicate = pred.parse()?;
+        ruleset.rules.push(Rule {
+            name: name.to_owned(),
+            args: pred.segment_args(mode),
+            pred,
+        });
+    }
+    Ok(rulesets)
+}
+
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String> {
+    args.next().ok_or_else(|| anyhow!("{flag} needs a value"))
+}
+
+fn main() -> Result<()> {
+    let mut rules_path = None;
+    let mut report = None;
+    let mut mode = Mode::Byte;
+    let mut split_dir = None;
+    let mut stats = false;
+    let mut generate_len = None;
+    let mut count = 1;
+    let mut seed = 0;
+    let mut alphabet: String = ('a'..='z').collect();
+    let mut pass: Vec<String> = Vec::new();
+    let mut fail: Vec<String> = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--explain" => report = Some(flag_value(&mut args, &arg)?.parse::<Report>()?),
+            "--mode" => mode = flag_value(&mut args, &arg)?.parse()?,
+            "--split" => split_dir = Some(flag_value(&mut args, &arg)?),
+            "--stats" => stats = true,
+            "--generate" => generate_len = Some(flag_value(&mut args, &arg)?.parse()?),
+            "--count" => count = flag_value(&mut args, &arg)?.parse()?,
+            "--seed" => seed = flag_value(&mut args, &arg)?.parse()?,
+            "--alphabet" => alphabet = flag_value(&mut args, &arg)?,
+            "--pass" => pass.extend(flag_value(&mut args, &arg)?.split(',').map(str::to_owned)),
+            "--fail" => fail.extend(flag_value(&mut args, &arg)?.split(',').map(str::to_owned)),
+            _ if !arg.starts_with("--") && rules_path.is_none() => rules_path = Some(arg),
+            _ => return Err(anyhow!("Unknown argument {arg:?}")),
+        }
+    }
+    let config = match rules_path {
+        Some(path) => fs::read_to_string(path)?,
+        None => DEFAULT_RULES.to_owned(),
+    };
+    let rulesets = parse_rules(&config, mode)?;
+
+    if let Some(len) = generate_len {
+        let mut rng = Rng(seed);
+        if pass.is_empty() && fail.is_empty() {
+            // Cover every pass/fail combination of every rule. Rules of the
+            // same name in different rule sets may differ, so keep them all.
+            const MAX_RULES: usize = 16;
+            let rules: Vec<(&str, &Rule)> = rulesets
+                .iter()
+                .flat_map(|ruleset| {
+                    ruleset
+                        .rules
+                        .iter()
+                        .map(|rule| (ruleset.name.as_str(), rule))
+                })
+                .collect();
+            if rules.len() > MAX_RULES {
+                let n = rules.len();
+                return Err(anyhow!(
+                    "{n} rules are too many to combine, use --pass or --fail"
+                ));
+            }
+            let labels: Vec<String> = rules
+                .iter()
+                .map(|&(ruleset, rule)| {
+                    match rules.iter().filter(|(_, r)| r.name == rule.name).count() {
+                        1 => rule.name.clone(),
+                        _ => format!("[{ruleset}]{}", rule.name),
+                    }
+                })
+                .collect();
+            for combination in 0..1u32 << rules.len() {
+                let want = |i: usize| combination & (1 << i) != 0;
+                let constraints: Vec<(&Rule, bool)> = rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(_, rule))| (rule, want(i)))
+                    .collect();
+                let label = labels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, label)| format!("{}{label}", if want(i) { '+' } else { '-' }))
+                    .join(" ");
+                println!("# {label}");
+                match generate(&constraints, &alphabet, len, count, mode, &mut rng) {
+                    Ok(strings) => {
+                        strings.iter().for_each(|s| println!("{s}"));
+                        if strings.len() < count {
+                            println!("# only found {} of {count} strings", strings.len());
+                        }
+                    }
+                    Err(e) => println!("# {e}"),
+                }
+            }
+        } else {
+            let mut constraints = Vec::new();
+            for name in &pass {
+                constraints.push((find_rule(&rulesets, name)?, true));
+            }
+            for name in &fail {
+                constraints.push((find_rule(&rulesets, name)?, false));
+            }
+            let strings = generate(&constraints, &alphabet, len, count, mode, &mut rng)?;
+            for s in &strings {
+                println!("{s}");
+            }
+            if strings.len() < count {
+                return Err(anyhow!("only found {} of {count} strings", strings.len()));
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(report) = report {
+        loop {
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .expect("Failed to read line");
+            let line = line.trim();
+            if line.is_empty() {
+                break; // stop on first empty line
+            }
+            explain(line, &rulesets, mode, report);
+        }
+        return Ok(());
+    }
+
+    let split = match split_dir {
+        Some(dir) => Some(open_split_files(&dir, &rulesets)?),
+        None => None,
+    };
+    let tally = classify_all(&rulesets, mode, split)?;
+    for (r, ruleset) in rulesets.iter().enumerate() {
+        println!("{}: {}", ruleset.name, tally.nice[r]);
+        if stats {
+            for (rule, hits) in ruleset.rules.iter().zip(&tally.hits[r]) {
+                println!("  {}: {hits}", rule.name);
+            }
         }
     }
-    println!("Part 1: {part1}");
-    println!("Part 2: {part2}");
+    Ok(())
 }
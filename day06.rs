@@ -1,40 +1,203 @@
-use std::io;
-use std::ops::Add;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::ops::{Add, Range};
 use std::str::FromStr;
+use std::thread;
 
 use anyhow::{anyhow, Error, Result};
 use array2d::Array2D;
+use itertools::Itertools;
 
+const DEFAULT_SEMANTICS: &str = "
+[Part 1]
+turn on: set 1
+turn off: set 0
+toggle: xor 1
+dim by: sub N
+set to: set N, min 1
+multiply by: mul N, min 1
+
+[Part 2]
+turn on: add 1
+turn off: sub 1
+toggle: add 2
+dim by: sub N
+set to: set N
+multiply by: mul N
+";
+
+/// An instruction verb, with its numeric argument if any (e.g. "dim by 3").
 #[derive(Clone, Debug, PartialEq)]
-enum Instruction {
-    TurnOn,
-    TurnOff,
-    Toggle,
+struct Instruction {
+    verb: String,
+    arg: Option<u32>,
 }
 
-use Instruction::*;
-
 impl FromStr for Instruction {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "turn on" => Ok(Self::TurnOn),
-            "turn off" => Ok(Self::TurnOff),
-            "toggle" => Ok(Self::Toggle),
-            _ => Err(anyhow!("Failed to parse {s:?}")),
+        let (args, verb): (Vec<&str>, Vec<&str>) = s
+            .split(' ')
+            .partition(|word| word.bytes().all(|b| b.is_ascii_digit()));
+        match args[..] {
+            _ if verb.is_empty() => Err(anyhow!("Failed to parse {s:?}")),
+            [] => Ok(Instruction {
+                verb: verb.join(" "),
+                arg: None,
+            }),
+            [arg] => Ok(Instruction {
+                verb: verb.join(" "),
+                arg: Some(arg.parse()?),
+            }),
+            _ => Err(anyhow!("Too many arguments in {s:?}")),
         }
     }
 }
 
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.arg {
+            Some(arg) => write!(f, "{} {arg}", self.verb),
+            None => write!(f, "{}", self.verb),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Literal(u32),
+    Arg,
+}
+
+/// One step in how a verb changes the value of a light.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Effect {
+    Set(Operand),
+    Add(Operand),
+    Sub(Operand),
+    Mul(Operand),
+    Xor(Operand),
+    Min(Operand),
+}
+
+impl FromStr for Effect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (op, operand) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("missing operand in {s:?}"))?;
+        let operand = match operand.trim() {
+            "N" => Operand::Arg,
+            n => Operand::Literal(n.parse()?),
+        };
+        match op {
+            "set" => Ok(Effect::Set(operand)),
+            "add" => Ok(Effect::Add(operand)),
+            "sub" => Ok(Effect::Sub(operand)),
+            "mul" => Ok(Effect::Mul(operand)),
+            "xor" => Ok(Effect::Xor(operand)),
+            "min" => Ok(Effect::Min(operand)),
+            _ => Err(anyhow!("Unknown effect {op:?}")),
+        }
+    }
+}
+
+impl Effect {
+    fn operand(&self) -> Operand {
+        match *self {
+            Effect::Set(n) | Effect::Add(n) | Effect::Sub(n) => n,
+            Effect::Mul(n) | Effect::Xor(n) | Effect::Min(n) => n,
+        }
+    }
+}
+
+fn apply_effects(effects: &[Effect], arg: Option<u32>, value: u32) -> u32 {
+    effects.iter().fold(value, |value, effect| {
+        let operand = |operand| match operand {
+            Operand::Literal(n) => n,
+            Operand::Arg => arg.unwrap_or(0),
+        };
+        match *effect {
+            Effect::Set(n) => operand(n),
+            Effect::Add(n) => value.saturating_add(operand(n)),
+            Effect::Sub(n) => value.saturating_sub(operand(n)),
+            Effect::Mul(n) => value.saturating_mul(operand(n)),
+            Effect::Xor(n) => value ^ operand(n),
+            Effect::Min(n) => value.min(operand(n)),
+        }
+    })
+}
+
+/// A rule table mapping each verb to the chain of effects it has on a light.
+#[derive(Debug)]
+struct Semantics {
+    name: String,
+    rules: Vec<(String, Vec<Effect>)>,
+}
+
+impl Semantics {
+    fn effects(&self, instruction: &Instruction) -> Result<&[Effect]> {
+        let effects = self
+            .rules
+            .iter()
+            .find(|(verb, _)| *verb == instruction.verb)
+            .map(|(_, effects)| effects.as_slice())
+            .ok_or_else(|| anyhow!("[{}] has no rule for {:?}", self.name, instruction.verb))?;
+        let needs_arg = effects
+            .iter()
+            .any(|effect| effect.operand() == Operand::Arg);
+        if needs_arg && instruction.arg.is_none() {
+            return Err(anyhow!("{:?} needs an argument", instruction.verb));
+        }
+        Ok(effects)
+    }
+}
+
+/// Parse semantics from a config with one `[name]` header per semantics,
+/// followed by `verb: effect, effect...` lines.
+fn parse_semantics(config: &str) -> Result<Vec<Semantics>> {
+    let mut semantics: Vec<Semantics> = Vec::new();
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            semantics.push(Semantics {
+                name: name.to_owned(),
+                rules: Vec::new(),
+            });
+            continue;
+        }
+        let (verb, effects) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("missing ':' in {line:?}"))?;
+        let effects = effects
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Effect>>>()?;
+        semantics
+            .last_mut()
+            .ok_or_else(|| anyhow!("verb {verb:?} outside of a [semantics]"))?
+            .rules
+            .push((verb.trim().to_owned(), effects));
+    }
+    Ok(semantics)
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 struct Point {
-    x: usize,
-    y: usize,
+    x: i64,
+    y: i64,
 }
 
 impl Point {
-    fn new(x: usize, y: usize) -> Self {
+    fn new(x: i64, y: i64) -> Self {
         Point { x, y }
     }
 }
@@ -47,6 +210,12 @@ impl Add for Point {
     }
 }
 
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.x, self.y)
+    }
+}
+
 impl FromStr for Point {
     type Err = Error;
 
@@ -66,74 +235,887 @@ struct Area {
 }
 
 impl Area {
-    fn new(tl: Point, br: Point) -> Self {
-        assert!(tl.x < br.x && tl.y < br.y);
+    /// The half-open area between two corners, given in any order.
+    fn new(a: Point, b: Point) -> Self {
+        Area {
+            tl: Point::new(a.x.min(b.x), a.y.min(b.y)),
+            br: Point::new(a.x.max(b.x), a.y.max(b.y)),
+        }
+    }
+
+    /// The area between two inclusive corners, given in any order.
+    fn from_corners(a: Point, b: Point) -> Self {
+        let area = Area::new(a, b);
+        Area::new(area.tl, area.br + Point::new(1, 1))
+    }
+
+    fn union(&self, other: &Area) -> Area {
+        Area::new(
+            Point::new(self.tl.x.min(other.tl.x), self.tl.y.min(other.tl.y)),
+            Point::new(self.br.x.max(other.br.x), self.br.y.max(other.br.y)),
+        )
+    }
+
+    /// The overlap of two areas, which is empty if they don't overlap.
+    fn intersect(&self, other: &Area) -> Area {
+        let tl = Point::new(self.tl.x.max(other.tl.x), self.tl.y.max(other.tl.y));
+        let br = Point::new(
+            self.br.x.min(other.br.x).max(tl.x),
+            self.br.y.min(other.br.y).max(tl.y),
+        );
         Area { tl, br }
     }
+
+    fn is_empty(&self) -> bool {
+        self.tl.x == self.br.x || self.tl.y == self.br.y
+    }
+
+    fn width(&self) -> usize {
+        self.br.x.abs_diff(self.tl.x) as usize
+    }
+
+    fn height(&self) -> usize {
+        self.br.y.abs_diff(self.tl.y) as usize
+    }
+
+    fn everything() -> Self {
+        Area::new(
+            Point::new(i64::MIN, i64::MIN),
+            Point::new(i64::MAX, i64::MAX),
+        )
+    }
+
+    fn contains(&self, p: &Point) -> bool {
+        (self.tl.x..self.br.x).contains(&p.x) && (self.tl.y..self.br.y).contains(&p.y)
+    }
+}
+
+impl fmt::Display for Area {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let last = Point::new(self.br.x - 1, self.br.y - 1);
+        write!(f, "{} through {last}", self.tl)
+    }
+}
+
+impl FromStr for Area {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (upper_left, bottom_right) = s
+            .split_once(" through ")
+            .ok_or_else(|| anyhow!("missing 'through' in {s:?}"))?;
+        Ok(Area::from_corners(
+            upper_left.parse()?,
+            bottom_right.parse()?,
+        ))
+    }
 }
 
 fn parse(line: &str) -> (Instruction, Area) {
     let words: Vec<&str> = line.rsplitn(4, ' ').collect();
     let instruction = words[3].parse::<Instruction>().unwrap();
-    let upper_left = words[2].parse::<Point>().unwrap();
-    assert!(words[1] == "through");
-    let bottom_right = words[0].parse::<Point>().unwrap();
-    let area = Area::new(upper_left, bottom_right + Point { x: 1, y: 1 });
+    let area = line[words[3].len() + 1..].parse::<Area>().unwrap();
     (instruction, area)
 }
 
-fn main() {
-    let input: Vec<_> = io::stdin()
-        .lines()
-        .map(Result::unwrap)
-        .map(|s| parse(&s))
-        .collect();
+trait Grid {
+    fn apply(&mut self, area: &Area, f: impl FnMut(u32) -> u32);
+    fn get(&self, p: &Point) -> u32;
+    fn sum(&self) -> u128;
+
+    /// Sum `f` of the value of each light inside `area`.
+    fn total_in(&self, area: &Area, f: impl Fn(u32) -> u128) -> u128;
+
+    /// The stored values covering `area`, in the order `apply` visits them.
+    fn save(&self, area: &Area) -> Vec<u32>;
+
+    /// Put back values from `save`.
+    fn restore(&mut self, area: &Area, values: &[u32]);
+
+    /// Apply a run of instructions.
+    fn apply_all(&mut self, steps: &[(Instruction, Area)], semantics: &Semantics) -> Result<()> {
+        for (instruction, area) in steps {
+            let effects = semantics.effects(instruction)?;
+            self.apply(area, |value| apply_effects(effects, instruction.arg, value));
+        }
+        Ok(())
+    }
+}
+
+/// The brute-force grid, with one cell per light. It starts out empty and
+/// grows to cover each area that instructions are applied to.
+struct DenseGrid {
+    bounds: Area,
+    cells: Array2D<u32>,
+}
+
+impl DenseGrid {
+    fn new() -> Self {
+        DenseGrid {
+            bounds: Area::new(Point::new(0, 0), Point::new(0, 0)),
+            cells: Array2D::filled_with(0, 0, 0),
+        }
+    }
+
+    fn index(&self, p: &Point) -> (usize, usize) {
+        (
+            (p.x - self.bounds.tl.x) as usize,
+            (p.y - self.bounds.tl.y) as usize,
+        )
+    }
+
+    fn grow_to(&mut self, area: &Area) {
+        let bounds = if self.bounds.is_empty() {
+            area.clone()
+        } else {
+            self.bounds.union(area)
+        };
+        if bounds == self.bounds {
+            return;
+        }
+        let mut grown = DenseGrid {
+            cells: Array2D::filled_with(0, bounds.width(), bounds.height()),
+            bounds,
+        };
+        for x in self.bounds.tl.x..self.bounds.br.x {
+            for y in self.bounds.tl.y..self.bounds.br.y {
+                let p = Point::new(x, y);
+                let (to, from) = (grown.index(&p), self.index(&p));
+                grown.cells[to] = self.cells[from];
+            }
+        }
+        *self = grown;
+    }
+}
 
-    // part 1
-    let mut bitmap = Array2D::filled_with(0u8, 1000, 1000);
-    for (instruction, area) in input.iter() {
+impl Grid for DenseGrid {
+    fn apply(&mut self, area: &Area, mut f: impl FnMut(u32) -> u32) {
+        if area.is_empty() {
+            return;
+        }
+        self.grow_to(area);
         for x in area.tl.x..area.br.x {
             for y in area.tl.y..area.br.y {
-                bitmap[(x, y)] = match instruction {
-                    TurnOn => 1,
-                    TurnOff => 0,
-                    Toggle => 1 - bitmap[(x, y)],
-                }
+                let i = self.index(&Point::new(x, y));
+                self.cells[i] = f(self.cells[i]);
             }
         }
     }
-    let mut sum: u32 = 0;
-    for x in 0..1000 {
-        for y in 0..1000 {
-            sum += bitmap[(x, y)] as u32;
+
+    fn get(&self, p: &Point) -> u32 {
+        if self.bounds.contains(p) {
+            self.cells[self.index(p)]
+        } else {
+            0
         }
     }
-    println!("Part 1: {}", sum);
 
-    // part 2
-    let mut bitmap = Array2D::filled_with(0u8, 1000, 1000);
-    for (instruction, area) in input.iter() {
+    fn sum(&self) -> u128 {
+        self.cells
+            .elements_row_major_iter()
+            .map(|&v| v as u128)
+            .sum()
+    }
+
+    fn total_in(&self, area: &Area, f: impl Fn(u32) -> u128) -> u128 {
+        let mut total = 0;
+        for x in area.tl.x.max(self.bounds.tl.x)..area.br.x.min(self.bounds.br.x) {
+            for y in area.tl.y.max(self.bounds.tl.y)..area.br.y.min(self.bounds.br.y) {
+                total += f(self.cells[self.index(&Point::new(x, y))]);
+            }
+        }
+        total
+    }
+
+    fn save(&self, area: &Area) -> Vec<u32> {
+        let mut values = Vec::with_capacity(area.width() * area.height());
         for x in area.tl.x..area.br.x {
             for y in area.tl.y..area.br.y {
-                bitmap[(x, y)] = match instruction {
-                    TurnOn => bitmap[(x, y)] + 1,
-                    TurnOff => {
-                        if bitmap[(x, y)] > 0 {
-                            bitmap[(x, y)] - 1
-                        } else {
-                            0
-                        }
+                values.push(self.get(&Point::new(x, y)));
+            }
+        }
+        values
+    }
+
+    fn restore(&mut self, area: &Area, values: &[u32]) {
+        let mut values = values.iter();
+        self.apply(area, |_| *values.next().unwrap());
+    }
+}
+
+/// The brute-force grid split into horizontal bands, with each band kept
+/// in its own `DenseGrid` and updated by its own thread.
+struct BandedGrid {
+    bands: Vec<(Area, DenseGrid)>,
+}
+
+impl BandedGrid {
+    fn new(bounds: &Area, count: usize) -> Self {
+        let height = ((bounds.height() + count - 1) / count).max(1) as i64;
+        let bands = (bounds.tl.y..bounds.br.y)
+            .step_by(height as usize)
+            .map(|y| {
+                let band = Area::new(
+                    Point::new(bounds.tl.x, y),
+                    Point::new(bounds.br.x, (y + height).min(bounds.br.y)),
+                );
+                (band, DenseGrid::new())
+            })
+            .collect();
+        BandedGrid { bands }
+    }
+}
+
+impl Grid for BandedGrid {
+    fn apply(&mut self, area: &Area, mut f: impl FnMut(u32) -> u32) {
+        for (band, grid) in self.bands.iter_mut() {
+            grid.apply(&area.intersect(band), &mut f);
+        }
+    }
+
+    fn get(&self, p: &Point) -> u32 {
+        self.bands
+            .iter()
+            .find(|(band, _)| band.contains(p))
+            .map_or(0, |(_, grid)| grid.get(p))
+    }
+
+    fn sum(&self) -> u128 {
+        self.bands.iter().map(|(_, grid)| grid.sum()).sum()
+    }
+
+    fn total_in(&self, area: &Area, f: impl Fn(u32) -> u128) -> u128 {
+        self.bands
+            .iter()
+            .map(|(_, grid)| grid.total_in(area, &f))
+            .sum()
+    }
+
+    fn save(&self, area: &Area) -> Vec<u32> {
+        self.bands
+            .iter()
+            .flat_map(|(band, grid)| grid.save(&area.intersect(band)))
+            .collect()
+    }
+
+    fn restore(&mut self, area: &Area, mut values: &[u32]) {
+        for (band, grid) in self.bands.iter_mut() {
+            let part = area.intersect(band);
+            let (head, tail) = values.split_at(part.width() * part.height());
+            grid.restore(&part, head);
+            values = tail;
+        }
+    }
+
+    fn apply_all(&mut self, steps: &[(Instruction, Area)], semantics: &Semantics) -> Result<()> {
+        let effects = steps
+            .iter()
+            .map(|(instruction, _)| semantics.effects(instruction))
+            .collect::<Result<Vec<_>>>()?;
+        thread::scope(|s| {
+            for (band, grid) in self.bands.iter_mut() {
+                let effects = &effects;
+                s.spawn(move || {
+                    for ((instruction, area), effects) in steps.iter().zip(effects) {
+                        grid.apply(&area.intersect(band), |value| {
+                            apply_effects(effects, instruction.arg, value)
+                        });
                     }
-                    Toggle => bitmap[(x, y)] + 2,
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A grid compressed along the distinct x and y edges of all areas.
+///
+/// Each cell stands for a rectangle of lights that every instruction
+/// updates together, so the cost depends on the number of edges and not
+/// on the grid area.
+struct CompressedGrid {
+    xs: Vec<i64>,
+    ys: Vec<i64>,
+    cells: Array2D<u32>,
+}
+
+impl CompressedGrid {
+    fn new<'a>(areas: impl Iterator<Item = &'a Area> + Clone) -> Self {
+        let edges = |coords: Vec<i64>| coords.into_iter().sorted().dedup().collect::<Vec<_>>();
+        let xs = edges(areas.clone().flat_map(|a| [a.tl.x, a.br.x]).collect());
+        let ys = edges(areas.flat_map(|a| [a.tl.y, a.br.y]).collect());
+        let cells = Array2D::filled_with(0, xs.len().saturating_sub(1), ys.len().saturating_sub(1));
+        CompressedGrid { xs, ys, cells }
+    }
+
+    /// The cells covering `area`, which must be one of the areas given to `new`.
+    fn cell_ranges(&self, area: &Area) -> (Range<usize>, Range<usize>) {
+        let index = |edges: &[i64], coord| edges.binary_search(&coord).unwrap();
+        (
+            index(&self.xs, area.tl.x)..index(&self.xs, area.br.x),
+            index(&self.ys, area.tl.y)..index(&self.ys, area.br.y),
+        )
+    }
+}
+
+impl Grid for CompressedGrid {
+    fn apply(&mut self, area: &Area, mut f: impl FnMut(u32) -> u32) {
+        let (xs, ys) = self.cell_ranges(area);
+        for x in xs {
+            for y in ys.clone() {
+                self.cells[(x, y)] = f(self.cells[(x, y)]);
+            }
+        }
+    }
+
+    fn get(&self, p: &Point) -> u32 {
+        let cell = |edges: &[i64], coord| edges.partition_point(|&e| e <= coord).checked_sub(1);
+        match (cell(&self.xs, p.x), cell(&self.ys, p.y)) {
+            (Some(x), Some(y)) => *self.cells.get(x, y).unwrap_or(&0),
+            _ => 0,
+        }
+    }
+
+    fn sum(&self) -> u128 {
+        let mut sum: u128 = 0;
+        for (x, xs) in self.xs.windows(2).enumerate() {
+            for (y, ys) in self.ys.windows(2).enumerate() {
+                let size = xs[1].abs_diff(xs[0]) as u128 * ys[1].abs_diff(ys[0]) as u128;
+                sum += self.cells[(x, y)] as u128 * size;
+            }
+        }
+        sum
+    }
+
+    fn total_in(&self, area: &Area, f: impl Fn(u32) -> u128) -> u128 {
+        let overlap = |edges: &[i64], lo: i64, hi: i64| {
+            let (lo, hi) = (edges[0].max(lo), edges[1].min(hi));
+            if lo < hi {
+                hi.abs_diff(lo) as u128
+            } else {
+                0
+            }
+        };
+        let mut total = 0;
+        for (x, xs) in self.xs.windows(2).enumerate() {
+            let width = overlap(xs, area.tl.x, area.br.x);
+            if width == 0 {
+                continue;
+            }
+            for (y, ys) in self.ys.windows(2).enumerate() {
+                let height = overlap(ys, area.tl.y, area.br.y);
+                total += f(self.cells[(x, y)]) * width * height;
+            }
+        }
+        total
+    }
+
+    fn save(&self, area: &Area) -> Vec<u32> {
+        let (xs, ys) = self.cell_ranges(area);
+        let mut values = Vec::with_capacity(xs.len() * ys.len());
+        for x in xs {
+            for y in ys.clone() {
+                values.push(self.cells[(x, y)]);
+            }
+        }
+        values
+    }
+
+    fn restore(&mut self, area: &Area, values: &[u32]) {
+        let (xs, ys) = self.cell_ranges(area);
+        let mut values = values.iter();
+        for x in xs {
+            for y in ys.clone() {
+                self.cells[(x, y)] = *values.next().unwrap();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Query {
+    At(Point),
+    Lit(Area),
+    Sum(Area),
+    History(Point),
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Failed to parse query {s:?}"))?;
+        match kind {
+            "at" => Ok(Query::At(rest.parse()?)),
+            "lit" => Ok(Query::Lit(rest.parse()?)),
+            "sum" => Ok(Query::Sum(rest.parse()?)),
+            "history" => Ok(Query::History(rest.parse()?)),
+            _ => Err(anyhow!("Unknown query {kind:?}")),
+        }
+    }
+}
+
+/// The instructions that touched the light at `p`, with its value after each.
+fn history(
+    input: &[(Instruction, Area)],
+    semantics: &Semantics,
+    p: &Point,
+) -> Result<Vec<(usize, u32)>> {
+    let mut value = 0;
+    let mut history = Vec::new();
+    for (i, (instruction, area)) in input.iter().enumerate() {
+        if area.contains(p) {
+            value = apply_effects(semantics.effects(instruction)?, instruction.arg, value);
+            history.push((i + 1, value));
+        }
+    }
+    Ok(history)
+}
+
+fn answer(
+    query: &Query,
+    grid: &impl Grid,
+    input: &[(Instruction, Area)],
+    semantics: &Semantics,
+) -> Result<String> {
+    Ok(match query {
+        Query::At(p) => grid.get(p).to_string(),
+        Query::Lit(area) => grid.total_in(area, |v| (v > 0) as u128).to_string(),
+        Query::Sum(area) => grid.total_in(area, |v| v as u128).to_string(),
+        Query::History(p) => history(input, semantics, p)?
+            .into_iter()
+            .map(|(i, value)| format!("#{i} {} -> {value}", input[i - 1].0))
+            .join(", "),
+    })
+}
+
+/// Where to write images of the grid, and how often.
+struct ImageOutput {
+    prefix: String,
+    extension: String,
+    every: Option<usize>,
+}
+
+impl ImageOutput {
+    fn new(path: &str, every: Option<usize>) -> Result<Self> {
+        match path.rsplit_once('.') {
+            Some((prefix, extension)) if extension == "pgm" || extension == "ppm" => {
+                Ok(ImageOutput {
+                    prefix: prefix.to_owned(),
+                    extension: extension.to_owned(),
+                    every,
+                })
+            }
+            _ => Err(anyhow!("Image path {path:?} must end in .pgm or .ppm")),
+        }
+    }
+
+    fn path(&self, semantics: &Semantics, frame: Option<usize>) -> String {
+        let stem: String = semantics
+            .name
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        match frame {
+            Some(frame) => format!("{}-{stem}-{frame:04}.{}", self.prefix, self.extension),
+            None => format!("{}-{stem}.{}", self.prefix, self.extension),
+        }
+    }
+}
+
+/// Write the lights within `bounds` as a binary PGM (or PPM) image, with
+/// brightness scaled to grey so that the brightest light is white.
+fn write_image(path: &str, grid: &impl Grid, bounds: &Area) -> Result<()> {
+    const MAX_PIXELS: usize = 1 << 28;
+    let (width, height) = (bounds.width(), bounds.height());
+    if width.saturating_mul(height) > MAX_PIXELS {
+        return Err(anyhow!("{width}x{height} grid is too large for an image"));
+    }
+    let mut values = Vec::with_capacity(width * height);
+    for y in bounds.tl.y..bounds.br.y {
+        for x in bounds.tl.x..bounds.br.x {
+            values.push(grid.get(&Point::new(x, y)));
+        }
+    }
+    let max = values.iter().copied().max().unwrap_or(0).max(1) as u64;
+    let greys = values.into_iter().map(|v| (v as u64 * 255 / max) as u8);
+    let (magic, pixels): (&str, Vec<u8>) = if path.ends_with(".ppm") {
+        ("P6", greys.flat_map(|g| [g, g, g]).collect())
+    } else {
+        ("P5", greys.collect())
+    };
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "{magic}\n{width} {height}\n255\n")?;
+    file.write_all(&pixels)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// The smallest area covering the origin and all instructions, so that
+/// images of different inputs line up with the top left light at (0, 0)
+/// whenever no coordinate is negative.
+fn bounds(input: &[(Instruction, Area)]) -> Area {
+    let origin = Area::new(Point::new(0, 0), Point::new(0, 0));
+    input
+        .iter()
+        .fold(origin, |bounds, (_, area)| bounds.union(area))
+}
+
+/// Apply the instructions, write any images, then print the total and the
+/// answers to any queries.
+fn run(
+    mut grid: impl Grid,
+    input: &[(Instruction, Area)],
+    semantics: &Semantics,
+    images: Option<&ImageOutput>,
+    queries: &[(String, Query)],
+) -> Result<u128> {
+    let bounds = bounds(input);
+    let every = images.and_then(|images| images.every);
+    let mut done = 0;
+    for steps in input.chunks(every.unwrap_or(input.len()).max(1)) {
+        grid.apply_all(steps, semantics)?;
+        done += steps.len();
+        if let Some(images) = images {
+            if matches!(every, Some(every) if done % every == 0) {
+                write_image(&images.path(semantics, Some(done)), &grid, &bounds)?;
+            }
+        }
+    }
+    if let Some(images) = images {
+        write_image(&images.path(semantics, None), &grid, &bounds)?;
+    }
+    let sum = grid.sum();
+    println!("{}: {sum}", semantics.name);
+    for (text, query) in queries {
+        println!("  {text}: {}", answer(query, &grid, input, semantics)?);
+    }
+    Ok(sum)
+}
+
+/// Steps back and forth through the instructions for every semantics at
+/// once, keeping an undo log of the values each applied step overwrote.
+struct Simulator<'a> {
+    input: &'a [(Instruction, Area)],
+    semantics: &'a [Semantics],
+    grids: Vec<CompressedGrid>,
+    undo: Vec<Vec<Vec<u32>>>,
+}
+
+impl<'a> Simulator<'a> {
+    fn new(input: &'a [(Instruction, Area)], semantics: &'a [Semantics]) -> Result<Self> {
+        for semantics in semantics {
+            for (instruction, _) in input {
+                semantics.effects(instruction)?;
+            }
+        }
+        Ok(Simulator {
+            input,
+            semantics,
+            grids: semantics
+                .iter()
+                .map(|_| CompressedGrid::new(input.iter().map(|(_, area)| area)))
+                .collect(),
+            undo: Vec::new(),
+        })
+    }
+
+    /// The number of instructions applied so far.
+    fn position(&self) -> usize {
+        self.undo.len()
+    }
+
+    fn forward(&mut self) -> bool {
+        let (instruction, area) = match self.input.get(self.position()) {
+            Some(step) => step,
+            None => return false,
+        };
+        let mut saved = Vec::with_capacity(self.grids.len());
+        for (grid, semantics) in self.grids.iter_mut().zip(self.semantics) {
+            let effects = semantics.effects(instruction).unwrap();
+            saved.push(grid.save(area));
+            grid.apply(area, |value| apply_effects(effects, instruction.arg, value));
+        }
+        self.undo.push(saved);
+        true
+    }
+
+    fn backward(&mut self) -> bool {
+        let saved = match self.undo.pop() {
+            Some(saved) => saved,
+            None => return false,
+        };
+        let (_, area) = &self.input[self.position()];
+        for (grid, values) in self.grids.iter_mut().zip(saved) {
+            grid.restore(area, &values);
+        }
+        true
+    }
+
+    fn goto(&mut self, n: usize) {
+        while self.position() < n && self.forward() {}
+        while self.position() > n && self.backward() {}
+    }
+
+    fn measure(&self, grid: usize, measure: Measure) -> u128 {
+        match measure {
+            Measure::Lit => self.grids[grid].total_in(&Area::everything(), |v| (v > 0) as u128),
+            Measure::Sum => self.grids[grid].sum(),
+        }
+    }
+
+    fn status(&self) -> String {
+        let step = match self.position() {
+            0 => "#0 (start)".to_owned(),
+            n => {
+                let (instruction, area) = &self.input[n - 1];
+                format!("#{n} {instruction} {area}")
+            }
+        };
+        let totals = self
+            .semantics
+            .iter()
+            .enumerate()
+            .map(|(i, semantics)| {
+                let (lit, sum) = (self.measure(i, Measure::Lit), self.measure(i, Measure::Sum));
+                format!("{}: lit {lit}, sum {sum}", semantics.name)
+            })
+            .join(" | ");
+        format!("{step} | {totals}")
+    }
+
+    /// Find the first position after which `measure` exceeds `threshold`.
+    /// Turning lights off or dimming them makes the totals go down as well
+    /// as up, so a binary search could land on a later crossing; instead
+    /// this steps forward from the start, which the undo log makes cheap.
+    fn bisect(&mut self, grid: usize, measure: Measure, threshold: u128) -> Option<usize> {
+        let start = self.position();
+        self.goto(0);
+        loop {
+            if self.measure(grid, measure) > threshold {
+                return Some(self.position());
+            }
+            if !self.forward() {
+                self.goto(start);
+                return None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Measure {
+    Lit,
+    Sum,
+}
+
+impl FromStr for Measure {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lit" => Ok(Measure::Lit),
+            "sum" => Ok(Measure::Sum),
+            _ => Err(anyhow!("Unknown measure {s:?}")),
+        }
+    }
+}
+
+fn step_command(sim: &mut Simulator, line: &str) -> Result<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let count = |n: Option<&&str>| n.map_or(Ok(1), |n| n.parse::<usize>());
+    match words[..] {
+        [] | ["n" | "next", ..] => {
+            for _ in 0..count(words.get(1))? {
+                sim.forward();
+            }
+        }
+        ["p" | "prev", ..] => {
+            for _ in 0..count(words.get(1))? {
+                sim.backward();
+            }
+        }
+        ["goto", n] => sim.goto(n.parse()?),
+        ["bisect", measure, threshold, ref name @ ..] => {
+            let grid = match name {
+                [] => 0,
+                name => {
+                    let name = name.join(" ");
+                    sim.semantics
+                        .iter()
+                        .position(|semantics| semantics.name == name)
+                        .ok_or_else(|| anyhow!("Unknown semantics {name:?}"))?
                 }
+            };
+            if sim
+                .bisect(grid, measure.parse()?, threshold.parse()?)
+                .is_none()
+            {
+                return Ok(format!("{measure} never exceeds {threshold}"));
+            }
+        }
+        ["show"] => (),
+        _ => return Err(anyhow!("Unknown command {line:?}")),
+    }
+    Ok(sim.status())
+}
+
+/// Step through the instructions in `path`, reading commands from stdin.
+fn step(path: &str, semantics: &[Semantics]) -> Result<()> {
+    let input: Vec<_> = fs::read_to_string(path)?.lines().map(parse).collect();
+    let mut sim = Simulator::new(&input, semantics)?;
+    println!("{}", sim.status());
+    for line in io::stdin().lines() {
+        let line = line?;
+        if matches!(line.trim(), "q" | "quit") {
+            break;
+        }
+        match step_command(&mut sim, &line) {
+            Ok(status) => println!("{status}"),
+            Err(e) => println!("error: {e}"),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let mut brute_force = false;
+    let mut semantics_path = None;
+    let mut image_path = None;
+    let mut every = None;
+    let mut query_path = None;
+    let mut step_path = None;
+    let mut threads = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{arg} needs a value"));
+        match arg.as_str() {
+            "--brute-force" => brute_force = true,
+            "--semantics" => semantics_path = Some(value()?),
+            "--image" => image_path = Some(value()?),
+            "--frames" => {
+                let n: NonZeroUsize = value()?
+                    .parse()
+                    .map_err(|e| anyhow!("{arg} needs a positive count ({e})"))?;
+                every = Some(n.get());
+            }
+            "--query" => query_path = Some(value()?),
+            "--step" => step_path = Some(value()?),
+            "--parallel" => {
+                threads = Some(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+            }
+            "--threads" => {
+                let n: NonZeroUsize = value()?
+                    .parse()
+                    .map_err(|e| anyhow!("{arg} needs a positive count ({e})"))?;
+                threads = Some(n.get());
             }
+            _ => return Err(anyhow!("Unknown argument {arg:?}")),
         }
     }
-    let mut sum: u32 = 0;
-    for x in 0..1000 {
-        for y in 0..1000 {
-            sum += bitmap[(x, y)] as u32;
+    let config = match semantics_path {
+        Some(path) => fs::read_to_string(path)?,
+        None => DEFAULT_SEMANTICS.to_owned(),
+    };
+    let semantics = parse_semantics(&config)?;
+    if let Some(path) = step_path {
+        return step(&path, &semantics);
+    }
+    let images = match image_path {
+        Some(path) => Some(ImageOutput::new(&path, every)?),
+        None if every.is_some() => return Err(anyhow!("--frames needs --image")),
+        None => None,
+    };
+    let queries = match query_path {
+        Some(path) => fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| Ok((line.to_owned(), line.parse()?)))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let input: Vec<_> = io::stdin()
+        .lines()
+        .map(Result::unwrap)
+        .map(|s| parse(&s))
+        .collect();
+
+    for semantics in &semantics {
+        if let Some(threads) = threads {
+            let grid = BandedGrid::new(&bounds(&input), threads);
+            run(grid, &input, semantics, images.as_ref(), &queries)?;
+        } else if brute_force {
+            run(
+                DenseGrid::new(),
+                &input,
+                semantics,
+                images.as_ref(),
+                &queries,
+            )?;
+        } else {
+            let grid = CompressedGrid::new(input.iter().map(|(_, area)| area));
+            run(grid, &input, semantics, images.as_ref(), &queries)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overlapping instructions, with negative and reversed corners.
+    const INSTRUCTIONS: &str = "
+        turn on 0,0 through 9,9
+        toggle 5,5 through 14,12
+        turn off 3,-4 through 7,6
+        dim by 2 12,12 through 2,2
+        toggle -6,-3 through 1,8
+        set to 4 -2,3 through 4,3
+        multiply by 3 0,0 through 11,11
+        turn on 8,1 through 8,1
+    ";
+
+    #[test]
+    fn grids_agree() {
+        let input: Vec<_> = INSTRUCTIONS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(parse)
+            .collect();
+        let bounds = bounds(&input);
+        for semantics in &parse_semantics(DEFAULT_SEMANTICS).unwrap() {
+            let mut dense = DenseGrid::new();
+            dense.apply_all(&input, semantics).unwrap();
+            let mut compressed = CompressedGrid::new(input.iter().map(|(_, area)| area));
+            compressed.apply_all(&input, semantics).unwrap();
+            assert_eq!(compressed.sum(), dense.sum(), "{}", semantics.name);
+            let banded: Vec<BandedGrid> = [1, 3, 7]
+                .into_iter()
+                .map(|threads| {
+                    let mut grid = BandedGrid::new(&bounds, threads);
+                    grid.apply_all(&input, semantics).unwrap();
+                    grid
+                })
+                .collect();
+            for grid in &banded {
+                assert_eq!(grid.sum(), dense.sum(), "{}", semantics.name);
+            }
+            // Every light, including a margin around the instructions
+            for y in bounds.tl.y - 2..bounds.br.y + 2 {
+                for x in bounds.tl.x - 2..bounds.br.x + 2 {
+                    let p = Point::new(x, y);
+                    let expected = dense.get(&p);
+                    assert_eq!(compressed.get(&p), expected, "{} at {p}", semantics.name);
+                    for grid in &banded {
+                        assert_eq!(grid.get(&p), expected, "{} at {p}", semantics.name);
+                    }
+                }
+            }
         }
     }
-    println!("Part 2: {}", sum);
 }
@@ -1,14 +1,139 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::error;
+use std::fmt::{self, Write};
 use std::io;
+use std::num::{NonZeroUsize, ParseIntError};
+use std::str::FromStr;
+use std::thread;
 
-#[derive(Debug)]
+use anyhow::{anyhow, Error, Result};
+use itertools::Itertools;
+
+/// The number of bits in every value of a circuit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Width(u32);
+
+impl Width {
+    fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.0)
+    }
+}
+
+impl Default for Width {
+    fn default() -> Self {
+        Width(16)
+    }
+}
+
+impl FromStr for Width {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" | "16" | "32" | "64" => Ok(Width(s.parse()?)),
+            _ => Err(anyhow!("Unsupported width {s:?} (use 8, 16, 32 or 64)")),
+        }
+    }
+}
+
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinOp {
+    And,
+    Or,
+    Xor,
+    Nand,
+    Nor,
+    Add,
+    Sub,
+    Mul,
+    LShift,
+    RShift,
+}
+
+impl BinOp {
+    fn keyword(self) -> &'static str {
+        match self {
+            BinOp::And => "AND",
+            BinOp::Or => "OR",
+            BinOp::Xor => "XOR",
+            BinOp::Nand => "NAND",
+            BinOp::Nor => "NOR",
+            BinOp::Add => "ADD",
+            BinOp::Sub => "SUB",
+            BinOp::Mul => "MUL",
+            BinOp::LShift => "LSHIFT",
+            BinOp::RShift => "RSHIFT",
+        }
+    }
+
+    /// The infix operator, where `NAND` and `NOR` use the operator they negate.
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::And | BinOp::Nand => "&",
+            BinOp::Or | BinOp::Nor => "|",
+            BinOp::Xor => "^",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::LShift => "<<",
+            BinOp::RShift => ">>",
+        }
+    }
+
+    /// Arithmetic wraps around, and shifting by the width or more gives 0.
+    fn apply(self, a: u64, b: u64, width: Width) -> u64 {
+        let shift = |f: fn(u64, u32) -> Option<u64>| {
+            u32::try_from(b).ok().and_then(|b| f(a, b)).unwrap_or(0)
+        };
+        let value = match self {
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Nand => !(a & b),
+            BinOp::Nor => !(a | b),
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::LShift => shift(u64::checked_shl),
+            BinOp::RShift => shift(u64::checked_shr),
+        };
+        value & width.mask()
+    }
+}
+
+impl FromStr for BinOp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "AND" => Ok(BinOp::And),
+            "OR" => Ok(BinOp::Or),
+            "XOR" => Ok(BinOp::Xor),
+            "NAND" => Ok(BinOp::Nand),
+            "NOR" => Ok(BinOp::Nor),
+            "ADD" => Ok(BinOp::Add),
+            "SUB" => Ok(BinOp::Sub),
+            "MUL" => Ok(BinOp::Mul),
+            "LSHIFT" => Ok(BinOp::LShift),
+            "RSHIFT" => Ok(BinOp::RShift),
+            _ => Err(anyhow!("Unknown gate {s:?}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 enum Operation {
     Id(String),
     Not(String),
-    RShift(String, u16),
-    LShift(String, u16),
-    And(String, String),
-    Or(String, String),
+    Binary(BinOp, String, String),
 }
 
 use Operation::*;
@@ -17,118 +142,2272 @@ impl Operation {
     fn parse(words: Vec<String>) -> Result<Self, String> {
         match words.len() {
             1 => Ok(Id(words[0].clone())),
-            2 => {
-                assert!(words[0] == "NOT");
-                Ok(Not(words[1].clone()))
-            }
-            3 => match words[1].as_str() {
-                "RSHIFT" => Ok(RShift(words[0].clone(), words[2].parse().unwrap())),
-                "LSHIFT" => Ok(LShift(words[0].clone(), words[2].parse().unwrap())),
-                "AND" => Ok(And(words[0].clone(), words[2].clone())),
-                "OR" => Ok(Or(words[0].clone(), words[2].clone())),
-                _ => unreachable!("Invalid operation! ({words:?})"),
+            2 if words[0] == "NOT" => Ok(Not(words[1].clone())),
+            3 => match words[1].parse() {
+                Ok(op) => Ok(Binary(op, words[0].clone(), words[2].clone())),
+                Err(_) => Err(format!("Invalid operation! ({words:?})")),
             },
-            _ => unreachable!("Invalid operation! ({words:?})"),
+            _ => Err(format!("Invalid operation! ({words:?})")),
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Id(a) => write!(f, "{a}"),
+            Not(a) => write!(f, "NOT {a}"),
+            Binary(op, a, b) => write!(f, "{a} {} {b}", op.keyword()),
+        }
+    }
+}
+
+impl Operation {
+    fn operands(&self) -> Vec<&str> {
+        match self {
+            Id(a) | Not(a) => vec![a],
+            Binary(_, a, b) => vec![a, b],
+        }
+    }
+
+    fn gate_label(&self) -> &'static str {
+        match self {
+            Id(_) => "ID",
+            Not(_) => "NOT",
+            Binary(op, _, _) => op.keyword(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 struct Unresolved {
     op: Operation,
     deps: HashSet<String>,
 }
 
 fn insert_unless_num(set: &mut HashSet<String>, s: &str) {
-    if s.parse::<u16>().is_err() {
+    if s.parse::<u64>().is_err() {
         set.insert(s.to_owned());
     };
 }
 
 impl Unresolved {
-    fn parse(words: Vec<String>) -> Result<Self, String> {
-        let op = Operation::parse(words).unwrap();
+    fn new(op: Operation) -> Self {
         let mut deps: HashSet<String> = HashSet::new();
-        match &op {
-            Id(a) => insert_unless_num(&mut deps, a),
-            Not(a) => insert_unless_num(&mut deps, a),
-            RShift(a, _) => insert_unless_num(&mut deps, a),
-            LShift(a, _) => insert_unless_num(&mut deps, a),
-            And(a, b) => {
-                insert_unless_num(&mut deps, a);
-                insert_unless_num(&mut deps, b);
-            }
-            Or(a, b) => {
-                insert_unless_num(&mut deps, a);
-                insert_unless_num(&mut deps, b);
+        for operand in op.operands() {
+            insert_unless_num(&mut deps, operand);
+        }
+        Unresolved { op, deps }
+    }
+
+    fn parse(words: Vec<String>) -> Result<Self, String> {
+        Ok(Unresolved::new(Operation::parse(words)?))
+    }
+
+    /// Reject literals that don't fit in `width` bits.
+    fn check(&self, width: Width) -> Result<()> {
+        for operand in self.op.operands() {
+            if matches!(operand.parse::<u64>(), Ok(n) if n > width.mask()) {
+                return Err(anyhow!(
+                    "{operand} does not fit in {width} bits ({})",
+                    self.op
+                ));
             }
-        };
-        Ok(Unresolved { op, deps })
+        }
+        Ok(())
     }
 }
 
-fn parse_or_resolve(s: &str, resolved: &HashMap<String, u16>) -> u16 {
-    s.parse::<u16>()
+fn parse_or_resolve(s: &str, resolved: &HashMap<String, u64>) -> u64 {
+    s.parse::<u64>()
         .unwrap_or_else(|_| *resolved.get(s).unwrap())
 }
 
-fn resolve(uop: &Unresolved, resolved: &HashMap<String, u16>) -> u16 {
+fn resolve(uop: &Unresolved, resolved: &HashMap<String, u64>, width: Width) -> u64 {
     match &uop.op {
         Id(a) => parse_or_resolve(a, resolved),
-        Not(a) => !parse_or_resolve(a, resolved),
-        RShift(a, n) => parse_or_resolve(a, resolved) >> n,
-        LShift(a, n) => parse_or_resolve(a, resolved) << n,
-        And(a, b) => parse_or_resolve(a, resolved) & parse_or_resolve(b, resolved),
-        Or(a, b) => parse_or_resolve(a, resolved) | parse_or_resolve(b, resolved),
+        Not(a) => !parse_or_resolve(a, resolved) & width.mask(),
+        Binary(op, a, b) => op.apply(
+            parse_or_resolve(a, resolved),
+            parse_or_resolve(b, resolved),
+            width,
+        ),
     }
 }
 
-fn resolve_all(parts: &HashMap<String, Unresolved>) -> HashMap<String, u16> {
+#[derive(Debug, PartialEq)]
+enum CircuitError {
+    Cycle(Vec<String>),
+    Missing {
+        inputs: Vec<String>,
+        unresolved: Vec<String>,
+    },
+}
+
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CircuitError::Cycle(wires) => {
+                write!(f, "Cycle: {} -> {}", wires.join(" -> "), wires[0])
+            }
+            CircuitError::Missing { inputs, unresolved } => write!(
+                f,
+                "Missing inputs {} leave these wires unresolved: {}",
+                inputs.join(", "),
+                unresolved.join(", ")
+            ),
+        }
+    }
+}
+
+impl error::Error for CircuitError {}
+
+/// Map each wire to the wires that depend on it.
+fn users(parts: &HashMap<String, Unresolved>) -> HashMap<&str, Vec<&str>> {
+    let mut users: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (dst, uop) in parts {
+        for dep in &uop.deps {
+            users.entry(dep.as_str()).or_default().push(dst.as_str());
+        }
+    }
+    users
+}
+
+/// Order the wires so that each comes after all of its dependencies.
+fn topo_order(parts: &HashMap<String, Unresolved>) -> Result<Vec<&str>, CircuitError> {
+    let users = users(parts);
+    let inputs: BTreeSet<&str> = users
+        .keys()
+        .copied()
+        .filter(|dep| !parts.contains_key(*dep))
+        .collect();
+    if !inputs.is_empty() {
+        // Everything downstream of a missing input is unresolved
+        let mut unresolved = BTreeSet::new();
+        let mut todo: Vec<&str> = inputs.iter().copied().collect();
+        while let Some(wire) = todo.pop() {
+            for &user in users.get(wire).into_iter().flatten() {
+                if unresolved.insert(user) {
+                    todo.push(user);
+                }
+            }
+        }
+        return Err(CircuitError::Missing {
+            inputs: inputs.into_iter().map(str::to_owned).collect(),
+            unresolved: unresolved.into_iter().map(str::to_owned).collect(),
+        });
+    }
+
+    let mut pending: HashMap<&str, usize> = parts
+        .iter()
+        .map(|(dst, uop)| (dst.as_str(), uop.deps.len()))
+        .collect();
+    let mut ready: Vec<&str> = pending
+        .iter()
+        .filter(|(_, &n)| n == 0)
+        .map(|(&wire, _)| wire)
+        .sorted()
+        .collect();
+    let mut order = Vec::with_capacity(parts.len());
+    while let Some(wire) = ready.pop() {
+        pending.remove(wire);
+        order.push(wire);
+        // Users are listed in hash order, so sort them to keep exports stable
+        for &user in users.get(wire).into_iter().flatten().sorted() {
+            let n = pending.get_mut(user).unwrap();
+            *n -= 1;
+            if *n == 0 {
+                ready.push(user);
+            }
+        }
+    }
+    if pending.is_empty() {
+        return Ok(order);
+    }
+
+    // Every wire left over has a dependency that is also left over, so
+    // following those dependencies must eventually revisit a wire.
+    let mut path: Vec<&str> = vec![pending.keys().copied().min().unwrap()];
+    loop {
+        let wire = path[path.len() - 1];
+        let next = parts[wire]
+            .deps
+            .iter()
+            .map(String::as_str)
+            .filter(|dep| pending.contains_key(dep))
+            .min()
+            .unwrap();
+        if let Some(start) = path.iter().position(|&w| w == next) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|&w| w.to_owned()).collect();
+            cycle.reverse(); // list each wire before the wires that use it
+            return Err(CircuitError::Cycle(cycle));
+        }
+        path.push(next);
+    }
+}
+
+fn resolve_all(
+    parts: &HashMap<String, Unresolved>,
+    width: Width,
+) -> Result<HashMap<String, u64>, CircuitError> {
     let mut resolved = HashMap::new();
-    while resolved.len() < parts.len() {
-        let before = resolved.len();
-        for (dst, uop) in parts {
-            if resolved.contains_key(dst) {
+    for dst in topo_order(parts)? {
+        resolved.insert(dst.to_owned(), resolve(&parts[dst], &resolved, width));
+    }
+    Ok(resolved)
+}
+
+/// Evaluates wires on demand and caches their values. Changing a wire only
+/// invalidates the wires downstream of it, so the next lookup recomputes
+/// just that part of the circuit.
+struct Evaluator {
+    parts: HashMap<String, Unresolved>,
+    users: HashMap<String, HashSet<String>>,
+    order: HashMap<String, usize>,
+    values: HashMap<String, u64>,
+    width: Width,
+}
+
+impl Evaluator {
+    fn new(parts: HashMap<String, Unresolved>, width: Width) -> Result<Self, CircuitError> {
+        let order = Self::positions(&parts)?;
+        let users = users(&parts)
+            .into_iter()
+            .map(|(wire, users)| {
+                let users = users.into_iter().map(str::to_owned).collect();
+                (wire.to_owned(), users)
+            })
+            .collect();
+        Ok(Evaluator {
+            parts,
+            users,
+            order,
+            values: HashMap::new(),
+            width,
+        })
+    }
+
+    fn positions(
+        parts: &HashMap<String, Unresolved>,
+    ) -> Result<HashMap<String, usize>, CircuitError> {
+        Ok(topo_order(parts)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, wire)| (wire.to_owned(), i))
+            .collect())
+    }
+
+    fn get(&mut self, wire: &str) -> Option<u64> {
+        if !self.parts.contains_key(wire) {
+            return None;
+        }
+        // Cached wires only depend on cached wires, so stop searching there
+        let mut stale = vec![];
+        let mut seen = HashSet::from([wire]);
+        let mut todo = vec![wire];
+        while let Some(wire) = todo.pop() {
+            if self.values.contains_key(wire) {
                 continue;
             }
-            // println!("{}: {:?}", dst, uop);
-            if uop
+            stale.push(wire);
+            for dep in &self.parts[wire].deps {
+                if seen.insert(dep) {
+                    todo.push(dep);
+                }
+            }
+        }
+        stale.sort_by_key(|&wire| self.order[wire]);
+        for wire in stale {
+            let value = resolve(&self.parts[wire], &self.values, self.width);
+            self.values.insert(wire.to_owned(), value);
+        }
+        self.values.get(wire).copied()
+    }
+
+    /// Replace the operation driving `wire`, and forget the values of it and
+    /// everything downstream. Changes that would break the circuit are undone.
+    fn set(&mut self, wire: &str, uop: Unresolved) -> Result<(), CircuitError> {
+        let new_deps = uop.deps.clone();
+        let old = self.parts.insert(wire.to_owned(), uop);
+        // The existing order still holds if every dependency comes first
+        let position = self.order.get(wire);
+        let ordered = |dep: &String| match (position, self.order.get(dep)) {
+            (Some(p), Some(d)) => d < p,
+            _ => false,
+        };
+        if !new_deps.iter().all(ordered) {
+            match Self::positions(&self.parts) {
+                Ok(order) => self.order = order,
+                Err(e) => {
+                    match old {
+                        Some(old) => self.parts.insert(wire.to_owned(), old),
+                        None => self.parts.remove(wire),
+                    };
+                    return Err(e);
+                }
+            }
+        }
+        for dep in old.iter().flat_map(|old| &old.deps) {
+            self.users.get_mut(dep).unwrap().remove(wire);
+        }
+        for dep in new_deps {
+            self.users.entry(dep).or_default().insert(wire.to_owned());
+        }
+        self.invalidate(wire);
+        Ok(())
+    }
+
+    fn invalidate(&mut self, wire: &str) {
+        let mut todo = vec![wire.to_owned()];
+        while let Some(wire) = todo.pop() {
+            // Uncached wires have no cached users
+            if self.values.remove(&wire).is_some() {
+                todo.extend(self.users.get(&wire).into_iter().flatten().cloned());
+            }
+        }
+    }
+}
+
+/// A wire forced to a value regardless of its driver.
+#[derive(Debug)]
+enum Fault {
+    StuckAt(String, u64),
+    Flip(String, u32),
+}
+
+impl Fault {
+    fn wire(&self) -> &str {
+        match self {
+            Fault::StuckAt(wire, _) | Fault::Flip(wire, _) => wire,
+        }
+    }
+
+    /// The faulty value of a wire whose fault-free value is `value`.
+    fn apply(&self, value: u64) -> u64 {
+        match self {
+            Fault::StuckAt(_, stuck) => *stuck,
+            Fault::Flip(_, bit) => value ^ 1 << bit,
+        }
+    }
+}
+
+impl FromStr for Fault {
+    type Err = Error;
+
+    /// Parse `wire=value` for stuck-at faults and `wire^bit` for bit flips.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((wire, value)) = s.split_once('=') {
+            Ok(Fault::StuckAt(wire.to_owned(), value.parse()?))
+        } else if let Some((wire, bit)) = s.split_once('^') {
+            Ok(Fault::Flip(wire.to_owned(), bit.parse()?))
+        } else {
+            Err(anyhow!(
+                "Invalid fault {s:?} (use <wire>=<value> or <wire>^<bit>)"
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StuckAt(wire, value) => write!(f, "{wire} stuck at {value}"),
+            Fault::Flip(wire, bit) => write!(f, "{wire} with bit {bit} flipped"),
+        }
+    }
+}
+
+impl Evaluator {
+    /// The values of `wires` with `fault` injected. The evaluator is left
+    /// without the fault.
+    fn with_fault(&mut self, fault: &Fault, wires: &[&str]) -> Result<Vec<u64>> {
+        let wire = fault.wire();
+        let value = self
+            .get(wire)
+            .ok_or_else(|| anyhow!("Unknown wire {wire:?}"))?;
+        let fits = match fault {
+            Fault::StuckAt(_, stuck) => *stuck <= self.width.mask(),
+            Fault::Flip(_, bit) => *bit < self.width.0,
+        };
+        if !fits {
+            return Err(anyhow!("{fault} does not fit in {} bits", self.width));
+        }
+        let faulty = fault.apply(value);
+        let driver = self.parts[wire].clone();
+        self.set(wire, Unresolved::new(Id(faulty.to_string())))?;
+        let values = wires.iter().map(|wire| self.get(wire).unwrap()).collect();
+        self.set(wire, driver)?;
+        Ok(values)
+    }
+
+    /// `wire` and every wire that depends on it, in evaluation order.
+    fn downstream(&self, wire: &str) -> Vec<String> {
+        let mut seen = HashSet::from([wire.to_owned()]);
+        let mut todo = vec![wire.to_owned()];
+        while let Some(wire) = todo.pop() {
+            for user in self.users.get(&wire).into_iter().flatten() {
+                if seen.insert(user.clone()) {
+                    todo.push(user.clone());
+                }
+            }
+        }
+        seen.into_iter()
+            .filter(|wire| self.parts.contains_key(wire))
+            .sorted_by_key(|wire| self.order[wire])
+            .collect()
+    }
+}
+
+/// List the wires whose values change when `fault` is injected.
+fn fault_report(evaluator: &mut Evaluator, fault: &Fault) -> Result<String> {
+    let downstream = evaluator.downstream(fault.wire());
+    let wires: Vec<&str> = downstream.iter().map(String::as_str).collect();
+    let before: Vec<u64> = wires.iter().map(|w| evaluator.get(w).unwrap()).collect();
+    let after = evaluator.with_fault(fault, &wires)?;
+    let changed: Vec<String> = (0..wires.len())
+        .filter(|&i| before[i] != after[i])
+        .map(|i| format!("  {}: {} -> {}", wires[i], before[i], after[i]))
+        .collect();
+    Ok(format!(
+        "{fault} changes {} of {} wires downstream:\n{}\n",
+        changed.len(),
+        wires.len(),
+        changed.join("\n")
+    ))
+}
+
+/// Rank every wire by how many bits of `output` can depend on it: with the
+/// wire's value unknown and every other driver as given, the bits of
+/// `output` that can no longer be worked out are the ones it influences.
+fn influence_report(circuit: &Circuit, output: &str) -> Result<String> {
+    let width = circuit.width;
+    let mut influence = vec![];
+    for wire in circuit.parts.keys().sorted() {
+        let program = Program::compile(circuit, &[wire])?;
+        let slot = program.slot(output)?;
+        let mut values = vec![Bits::unknown(); program.gates.len()];
+        program.run_bits(&[Bits::unknown()], &mut values);
+        influence.push((wire, !values[slot].known & width.mask()));
+    }
+    influence.sort_by_key(|&(wire, bits)| (Reverse(bits.count_ones()), wire));
+
+    let mut report = format!("Bits of {output} that can depend on each wire:\n");
+    for (wire, bits) in influence {
+        let row: String = (0..width.0)
+            .rev()
+            .map(|j| if bits >> j & 1 == 1 { '#' } else { '.' })
+            .collect();
+        writeln!(report, "  {:>2}  {row}  {wire}", bits.count_ones())?;
+    }
+    Ok(report)
+}
+
+/// Where a gate reads an operand from.
+#[derive(Clone, Copy, Debug)]
+enum Arg {
+    Slot(usize),
+    Const(u64),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Gate {
+    Input(usize),
+    Id(Arg),
+    Not(Arg),
+    Binary(BinOp, Arg, Arg),
+}
+
+/// A circuit compiled to a flat list of gates in topological order, where
+/// gate `i` computes the value of slot `i`. The chosen input wires read
+/// their values from an input vector instead of from their gates.
+struct Program {
+    slots: HashMap<String, usize>,
+    gates: Vec<Gate>,
+    width: Width,
+}
+
+impl Program {
+    fn compile(circuit: &Circuit, inputs: &[&str]) -> Result<Self> {
+        let users = users(&circuit.parts);
+        let known = |wire: &str| circuit.parts.contains_key(wire) || users.contains_key(wire);
+        if let Some(input) = inputs.iter().find(|&&input| !known(input)) {
+            return Err(anyhow!("Unknown wire {input:?}"));
+        }
+        // Inputs need no driver, and their drivers' dependencies don't matter
+        let mut parts = circuit.parts.clone();
+        for input in inputs {
+            parts.insert(
+                input.to_string(),
+                Unresolved::parse(vec!["0".to_owned()]).unwrap(),
+            );
+        }
+        let order = topo_order(&parts)?;
+        let slots: HashMap<String, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(i, &wire)| (wire.to_owned(), i))
+            .collect();
+        let arg = |s: &str| match s.parse::<u64>() {
+            Ok(n) => Arg::Const(n),
+            Err(_) => Arg::Slot(slots[s]),
+        };
+        let gates = order
+            .iter()
+            .map(
+                |&wire| match inputs.iter().position(|&input| input == wire) {
+                    Some(k) => Gate::Input(k),
+                    None => match &parts[wire].op {
+                        Id(a) => Gate::Id(arg(a)),
+                        Not(a) => Gate::Not(arg(a)),
+                        Binary(op, a, b) => Gate::Binary(*op, arg(a), arg(b)),
+                    },
+                },
+            )
+            .collect();
+        Ok(Program {
+            slots,
+            gates,
+            width: circuit.width,
+        })
+    }
+
+    fn slot(&self, wire: &str) -> Result<usize> {
+        self.slots
+            .get(wire)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown wire {wire:?}"))
+    }
+
+    /// Compute every slot into `values`, which must hold one value per gate.
+    fn run(&self, inputs: &[u64], values: &mut [u64]) {
+        let mask = self.width.mask();
+        for (i, gate) in self.gates.iter().enumerate() {
+            let get = |arg: Arg| match arg {
+                Arg::Slot(slot) => values[slot],
+                Arg::Const(n) => n,
+            };
+            values[i] = match *gate {
+                Gate::Input(k) => inputs[k] & mask,
+                Gate::Id(a) => get(a),
+                Gate::Not(a) => !get(a) & mask,
+                Gate::Binary(op, a, b) => op.apply(get(a), get(b), self.width),
+            };
+        }
+    }
+
+    /// Like `run`, but with partially known inputs.
+    fn run_bits(&self, inputs: &[Bits], values: &mut [Bits]) {
+        for (i, gate) in self.gates.iter().enumerate() {
+            let get = |arg: Arg| match arg {
+                Arg::Slot(slot) => values[slot],
+                Arg::Const(n) => Bits::exact(n, self.width),
+            };
+            values[i] = match *gate {
+                Gate::Input(k) => inputs[k],
+                Gate::Id(a) => get(a),
+                Gate::Not(a) => get(a).not(),
+                Gate::Binary(op, a, b) => Bits::apply(op, get(a), get(b), self.width),
+            };
+        }
+    }
+}
+
+/// A partially known value: the bits set in `known` are equal to the same
+/// bits of `value`, and the other bits of `value` are 0.
+#[derive(Clone, Copy, Debug)]
+struct Bits {
+    known: u64,
+    value: u64,
+}
+
+impl Bits {
+    fn exact(value: u64, width: Width) -> Self {
+        Bits {
+            known: width.mask(),
+            value,
+        }
+    }
+
+    fn unknown() -> Self {
+        Bits { known: 0, value: 0 }
+    }
+
+    fn is_exact(self, width: Width) -> bool {
+        self.known == width.mask()
+    }
+
+    fn bit(self, i: u32) -> Option<bool> {
+        (self.known >> i & 1 == 1).then_some(self.value >> i & 1 == 1)
+    }
+
+    fn not(self) -> Self {
+        Bits {
+            known: self.known,
+            value: !self.value & self.known,
+        }
+    }
+
+    /// Ripple-carry addition, where each sum bit is known if both operand
+    /// bits and the carry into it are.
+    fn add(a: Self, b: Self, carry: bool, width: Width) -> Self {
+        let mut sum = Bits::unknown();
+        let mut carry = Some(carry);
+        for i in 0..width.0 {
+            let bits = [a.bit(i), b.bit(i), carry];
+            if let [Some(x), Some(y), Some(c)] = bits {
+                sum.known |= 1 << i;
+                sum.value |= u64::from(x ^ y ^ c) << i;
+            }
+            // The carry out is the majority, known once two bits agree
+            let count = |b| bits.iter().filter(|&&bit| bit == Some(b)).count();
+            carry = match (count(true), count(false)) {
+                (2.., _) => Some(true),
+                (_, 2..) => Some(false),
+                _ => None,
+            };
+        }
+        sum
+    }
+
+    fn apply(op: BinOp, a: Self, b: Self, width: Width) -> Self {
+        let mask = width.mask();
+        let low = |n: u32| match n.min(width.0) {
+            0 => 0,
+            n => mask >> (width.0 - n),
+        };
+        if a.is_exact(width) && b.is_exact(width) {
+            return Bits::exact(op.apply(a.value, b.value, width), width);
+        }
+        match op {
+            BinOp::And => Bits {
+                known: (a.known & b.known) | (a.known & !a.value) | (b.known & !b.value),
+                value: a.value & b.value,
+            },
+            BinOp::Or => Bits {
+                known: (a.known & b.known) | a.value | b.value,
+                value: a.value | b.value,
+            },
+            BinOp::Xor => Bits {
+                known: a.known & b.known,
+                value: (a.value ^ b.value) & a.known & b.known,
+            },
+            BinOp::Nand => Bits::apply(BinOp::And, a, b, width).not(),
+            BinOp::Nor => Bits::apply(BinOp::Or, a, b, width).not(),
+            BinOp::Add => Bits::add(a, b, false, width),
+            BinOp::Sub => Bits::add(a, b.not(), true, width),
+            BinOp::Mul => {
+                // The low bits of a product only depend on the low bits of
+                // its factors, and each factor's trailing zeros carry over.
+                let known = a.known.trailing_ones().min(b.known.trailing_ones());
+                let zeros = |x: Bits| (x.known & !x.value).trailing_ones();
+                Bits {
+                    known: low(known.max(zeros(a) + zeros(b))),
+                    value: a.value.wrapping_mul(b.value) & low(known),
+                }
+            }
+            BinOp::LShift | BinOp::RShift if b.is_exact(width) => {
+                let n = b.value;
+                if n >= u64::from(width.0) {
+                    return Bits::exact(0, width);
+                }
+                // Bits shifted in are known zeros
+                let filled = !op.apply(mask, n, width) & mask;
+                Bits {
+                    known: op.apply(a.known, n, width) | filled,
+                    value: op.apply(a.value, n, width),
+                }
+            }
+            BinOp::LShift | BinOp::RShift if a.is_exact(width) && a.value == 0 => a,
+            BinOp::LShift | BinOp::RShift => Bits::unknown(),
+        }
+    }
+}
+
+/// Run a program with a single input on every value of that input, on all
+/// cores, and list the values of slot `output` in input order.
+fn table(program: &Program, output: usize) -> Vec<u64> {
+    let count = 1u64 << program.width.0;
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get) as u64;
+    let chunk = (count + threads - 1) / threads;
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || {
+                    let mut values = vec![0; program.gates.len()];
+                    (t * chunk..count.min((t + 1) * chunk))
+                        .map(|value| {
+                            program.run(&[value], &mut values);
+                            values[output]
+                        })
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// How an output wire responds to each value of one input wire.
+struct Sensitivity {
+    input: String,
+    output: String,
+    width: Width,
+    table: Vec<u64>,
+}
+
+impl Sensitivity {
+    fn new(circuit: &Circuit, input: &str, output: &str) -> Result<Self> {
+        if circuit.width.0 > 16 {
+            return Err(anyhow!(
+                "{} bit inputs are too wide to sweep",
+                circuit.width
+            ));
+        }
+        let program = Program::compile(circuit, &[input])?;
+        Ok(Sensitivity {
+            input: input.to_owned(),
+            output: output.to_owned(),
+            width: circuit.width,
+            table: table(&program, program.slot(output)?),
+        })
+    }
+
+    /// For each input bit, the output bits that flipping it can change.
+    fn dependencies(&self) -> Vec<u64> {
+        (0..self.width.0)
+            .map(|i| {
+                let table = &self.table;
+                (0..table.len()).fold(0, |deps, v| deps | (table[v] ^ table[v ^ 1 << i]))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Sensitivity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (input, output) = (&self.input, &self.output);
+        let distinct: HashSet<u64> = self.table.iter().copied().collect();
+        writeln!(
+            f,
+            "{output} takes {} distinct values ({}..={}) over {} values of {input}",
+            distinct.len(),
+            distinct.iter().min().unwrap(),
+            distinct.iter().max().unwrap(),
+            self.table.len()
+        )?;
+        let fixed: Vec<usize> = (0..self.table.len())
+            .filter(|&v| self.table[v] == v as u64)
+            .collect();
+        match fixed.len() {
+            0 => writeln!(f, "No fixed points where {output} = {input}")?,
+            _ => writeln!(
+                f,
+                "Fixed points where {output} = {input}: {}",
+                fixed.iter().join(", ")
+            )?,
+        }
+
+        // One row per output bit and one column per input bit, high to low
+        let deps = self.dependencies();
+        let bits = (0..self.width.0).rev();
+        writeln!(
+            f,
+            "Bit dependencies ({output} bits down, {input} bits across):"
+        )?;
+        writeln!(f, "    {}", bits.clone().map(|i| format!("{i:x}")).join(""))?;
+        for j in bits.clone() {
+            let row: String = bits
+                .clone()
+                .map(|i| {
+                    if deps[i as usize] >> j & 1 == 1 {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(f, "{j:>2}  {row}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Searches for input values one bit at a time, lowest bits first, and
+/// backtracks as soon as the known bits of the output rule out the target.
+struct Search<'a> {
+    program: &'a Program,
+    output: usize,
+    target: u64,
+    limit: usize,
+    order: Vec<(usize, u32)>,
+    values: Vec<Bits>,
+    solutions: Vec<Vec<u64>>,
+}
+
+impl Search<'_> {
+    fn search(&mut self, inputs: &mut [Bits], depth: usize) {
+        if self.solutions.len() >= self.limit {
+            return;
+        }
+        self.program.run_bits(inputs, &mut self.values);
+        let output = self.values[self.output];
+        if (output.value ^ self.target) & output.known != 0 {
+            return;
+        }
+        let (k, i) = match self.order.get(depth) {
+            Some(&next) => next,
+            None => {
+                self.solutions
+                    .push(inputs.iter().map(|bits| bits.value).collect());
+                return;
+            }
+        };
+        for bit in [0, 1] {
+            inputs[k].known |= 1 << i;
+            inputs[k].value |= bit << i;
+            self.search(inputs, depth + 1);
+            inputs[k].known &= !(1 << i);
+            inputs[k].value &= !(1 << i);
+        }
+    }
+}
+
+/// Find up to `limit` assignments to the `free` wires, in the same order,
+/// that give `wire` the value `target`. The search only stops early once it
+/// has `limit` of them, so as long as `limit` is positive an empty result
+/// means there are none.
+fn solve(
+    circuit: &Circuit,
+    wire: &str,
+    target: u64,
+    free: &[&str],
+    limit: usize,
+) -> Result<Vec<Vec<u64>>> {
+    if free.is_empty() || free.contains(&wire) {
+        return Err(anyhow!("{wire:?} needs free inputs other than itself"));
+    }
+    if target > circuit.width.mask() {
+        return Err(anyhow!("{target} does not fit in {} bits", circuit.width));
+    }
+    let program = Program::compile(circuit, free)?;
+    let output = program.slot(wire)?;
+    if free.len() == 1 && circuit.width.0 <= 16 {
+        let table = table(&program, output);
+        let solutions = (0..table.len() as u64).filter(|&v| table[v as usize] == target);
+        return Ok(solutions.take(limit).map(|value| vec![value]).collect());
+    }
+    let mut search = Search {
+        program: &program,
+        output,
+        target,
+        limit,
+        order: (0..circuit.width.0)
+            .flat_map(|i| (0..free.len()).map(move |k| (k, i)))
+            .collect(),
+        values: vec![Bits::unknown(); program.gates.len()],
+        solutions: vec![],
+    };
+    search.search(&mut vec![Bits::unknown(); free.len()], 0);
+    Ok(search.solutions)
+}
+
+/// The propagation delay of each kind of gate, keyed by gate label. Gates
+/// without an entry take one time unit, except `ID` which is just a wire.
+#[derive(Default)]
+struct Delays(HashMap<String, u64>);
+
+impl Delays {
+    fn of(&self, op: &Operation) -> u64 {
+        let default = match op {
+            Id(_) => 0,
+            _ => 1,
+        };
+        self.0.get(op.gate_label()).copied().unwrap_or(default)
+    }
+}
+
+/// Parse delays from a config of `GATE: delay` lines, like `ADD: 4`.
+fn parse_delays(config: &str) -> Result<Delays> {
+    let mut delays = HashMap::new();
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (gate, delay) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("missing ':' in {line:?}"))?;
+        let gate = gate.trim();
+        if !matches!(gate, "ID" | "NOT") {
+            gate.parse::<BinOp>()?;
+        }
+        delays.insert(gate.to_owned(), delay.trim().parse()?);
+    }
+    Ok(Delays(delays))
+}
+
+/// When each wire's signal settles, and which dependency it waits for last.
+struct Timing {
+    arrival: HashMap<String, u64>,
+    last: HashMap<String, String>,
+}
+
+impl Timing {
+    fn new(parts: &HashMap<String, Unresolved>, delays: &Delays) -> Result<Self, CircuitError> {
+        let mut timing = Timing {
+            arrival: HashMap::new(),
+            last: HashMap::new(),
+        };
+        for wire in topo_order(parts)? {
+            let uop = &parts[wire];
+            let last = uop
                 .deps
-                .difference(&resolved.keys().cloned().collect())
-                .count()
-                == 0
-            {
-                resolved.insert(dst.to_owned(), resolve(uop, &resolved));
+                .iter()
+                .sorted()
+                .max_by_key(|&dep| timing.arrival[dep]);
+            let start = last.map_or(0, |dep| timing.arrival[dep]);
+            timing
+                .arrival
+                .insert(wire.to_owned(), start + delays.of(&uop.op));
+            if let Some(dep) = last {
+                timing.last.insert(wire.to_owned(), dep.clone());
             }
         }
-        if resolved.len() == before {
-            panic!("No progress!");
+        Ok(timing)
+    }
+
+    /// The chain of wires that `wire` waits for, starting from a source.
+    fn critical_path<'a>(&'a self, mut wire: &'a str) -> Vec<&'a str> {
+        let mut path = vec![wire];
+        while let Some(dep) = self.last.get(wire) {
+            wire = dep;
+            path.push(wire);
         }
+        path.reverse();
+        path
     }
-    resolved
 }
 
-fn main() {
+fn timing_report(circuit: &Circuit, output: &str, delays: &Delays) -> Result<String> {
+    if !circuit.parts.contains_key(output) {
+        return Err(anyhow!("Unknown wire {output:?}"));
+    }
+    let timing = Timing::new(&circuit.parts, delays)?;
+    let mut report = String::from("Arrival times:\n");
+    for (wire, arrival) in timing.arrival.iter().sorted_by_key(|&(w, &t)| (t, w)) {
+        writeln!(report, "  {wire}: {arrival}")?;
+    }
+    writeln!(report, "Critical path to {output}:")?;
+    for wire in timing.critical_path(output) {
+        let op = &circuit.parts[wire].op;
+        writeln!(report, "  {:>4}  {op} -> {wire}", timing.arrival[wire])?;
+    }
+    let gates = Timing::new(&circuit.parts, &Delays::default())?;
+    let depth = gates.arrival.values().max().unwrap_or(&0);
+    writeln!(report, "Logic depth: {depth} gates")?;
+    Ok(report)
+}
+
+/// Parse a `<operation> -> <wire>` line.
+fn parse_line(line: &str) -> Result<(String, Unresolved)> {
+    let mut words: Vec<String> = line.split_whitespace().map(|w| w.to_owned()).collect();
+    if words.len() < 3 || words[words.len() - 2] != "->" {
+        return Err(anyhow!("Failed to parse {line:?}"));
+    }
+    let dst = words.pop().unwrap();
+    words.pop(); // ->
+    Ok((dst, Unresolved::parse(words).map_err(|e| anyhow!(e))?))
+}
+
+struct Circuit {
+    width: Width,
+    parts: HashMap<String, Unresolved>,
+}
+
+/// Parse a circuit of `<operation> -> <wire>` lines, optionally with a
+/// `width <bits>` line choosing the word width (16 by default). Lines
+/// starting with `#` are comments.
+fn parse_circuit(lines: impl Iterator<Item = String>) -> Result<Circuit> {
+    let mut width = None;
     let mut parts: HashMap<String, Unresolved> = HashMap::new();
-    for line in io::stdin().lines().map(Result::unwrap) {
-        let mut words: Vec<String> = line.split(' ').map(|w| w.to_owned()).collect();
-        assert!(words.len() >= 3);
-        let dst = words.pop().unwrap();
-        let arrow = words.pop().unwrap();
-        assert!(arrow == "->");
-        assert!(!parts.contains_key(&dst));
-        parts.insert(dst.to_owned(), Unresolved::parse(words).unwrap());
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // A gate can read a wire called `width`, so only lines without an
+        // arrow choose the width
+        if let Some(bits) = line.strip_prefix("width ").filter(|_| !line.contains("->")) {
+            if width.replace(bits.trim().parse()?).is_some() {
+                return Err(anyhow!("Width given more than once"));
+            }
+            continue;
+        }
+        let (dst, uop) = parse_line(line)?;
+        if parts.insert(dst.clone(), uop).is_some() {
+            return Err(anyhow!("Wire {dst:?} has more than one driver"));
+        }
+    }
+    let width = width.unwrap_or_default();
+    for uop in parts.values() {
+        uop.check(width)?;
+    }
+    Ok(Circuit { width, parts })
+}
+
+/// Query and override the wires of a circuit loaded from a file.
+struct Repl {
+    path: String,
+    parts: HashMap<String, Unresolved>,
+    overrides: HashMap<String, Unresolved>,
+    evaluator: Evaluator,
+}
+
+impl Repl {
+    fn load(path: &str) -> Result<Self> {
+        let Circuit { width, parts } = Self::read(path)?;
+        Ok(Repl {
+            path: path.to_owned(),
+            evaluator: Evaluator::new(parts.clone(), width)?,
+            parts,
+            overrides: HashMap::new(),
+        })
+    }
+
+    fn read(path: &str) -> Result<Circuit> {
+        let text = std::fs::read_to_string(path)?;
+        parse_circuit(text.lines().map(str::to_owned))
+    }
+
+    /// Re-read the circuit file, keeping any overrides.
+    fn reload(&mut self) -> Result<()> {
+        let Circuit { width, parts } = Self::read(&self.path)?;
+        for uop in self.overrides.values() {
+            uop.check(width)?;
+        }
+        let mut circuit = parts.clone();
+        circuit.extend(self.overrides.clone());
+        self.evaluator = Evaluator::new(circuit, width)?;
+        self.parts = parts;
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str) -> Result<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["get", wire] => {
+                let value = self
+                    .evaluator
+                    .get(wire)
+                    .ok_or_else(|| anyhow!("Unknown wire {wire:?}"))?;
+                Ok(format!("{wire} = {value}"))
+            }
+            ["set", wire, ref expr @ ..] if !expr.is_empty() => {
+                let uop = Unresolved::parse(expr.iter().map(|&w| w.to_owned()).collect())
+                    .map_err(|e| anyhow!(e))?;
+                uop.check(self.evaluator.width)?;
+                let text = format!("{} -> {wire}", uop.op);
+                self.evaluator.set(wire, uop.clone())?;
+                self.overrides.insert(wire.to_owned(), uop);
+                Ok(text)
+            }
+            ["unset", wire] => {
+                if !self.overrides.contains_key(wire) {
+                    return Err(anyhow!("{wire:?} is not overridden"));
+                }
+                match self.parts.get(wire) {
+                    Some(uop) => self.evaluator.set(wire, uop.clone())?,
+                    None => {
+                        // Added by set, so drop it along with what it drives
+                        let mut circuit = self.evaluator.parts.clone();
+                        circuit.remove(wire);
+                        self.evaluator = Evaluator::new(circuit, self.evaluator.width)?;
+                    }
+                }
+                self.overrides.remove(wire);
+                Ok(format!("{wire} restored"))
+            }
+            ["deps", wire] => {
+                let uop = self
+                    .evaluator
+                    .parts
+                    .get(wire)
+                    .ok_or_else(|| anyhow!("Unknown wire {wire:?}"))?;
+                Ok(format!(
+                    "{} -> {wire}: {}",
+                    uop.op,
+                    uop.deps.iter().sorted().join(" ")
+                ))
+            }
+            ["users", wire] => {
+                let users = self.evaluator.users.get(wire).into_iter().flatten();
+                Ok(users.sorted().join(" "))
+            }
+            ["reload"] => {
+                self.reload()?;
+                Ok(format!("Reloaded {}", self.path))
+            }
+            _ => Err(anyhow!("Unknown command {line:?}")),
+        }
+    }
+}
+
+fn repl(path: &str) -> Result<()> {
+    let mut repl = Repl::load(path)?;
+    for line in io::stdin().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if matches!(line.trim(), "q" | "quit") {
+            break;
+        }
+        match repl.command(&line) {
+            Ok(output) => println!("{output}"),
+            Err(e) => println!("error: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// The wires that `wire` depends on, directly or indirectly, including itself.
+fn fan_in<'a>(parts: &'a HashMap<String, Unresolved>, wire: &'a str) -> HashSet<&'a str> {
+    let mut cone = HashSet::from([wire]);
+    let mut todo = vec![wire];
+    while let Some(wire) = todo.pop() {
+        for dep in parts.get(wire).into_iter().flat_map(|uop| &uop.deps) {
+            if cone.insert(dep) {
+                todo.push(dep);
+            }
+        }
+    }
+    cone
+}
+
+/// The operand that `op` always equals, if it needs no gate: the result of
+/// an operation on literals, the source of a copy, or the surviving side of
+/// an identity such as `x AND 65535` or `x LSHIFT 0`.
+fn simplify(op: &Operation, width: Width) -> Option<String> {
+    let literal = |s: &str| s.parse::<u64>().ok();
+    if op.operands().into_iter().all(|s| literal(s).is_some()) {
+        let uop = Unresolved::new(op.clone());
+        return Some(resolve(&uop, &HashMap::new(), width).to_string());
+    }
+    let mask = width.mask();
+    let zero = || Some("0".to_owned());
+    let (op, a, b) = match op {
+        Binary(op, a, b) => (op, a, b),
+        Id(a) => return Some(a.clone()),
+        _ => return None,
+    };
+    match (op, literal(a), literal(b)) {
+        (BinOp::And | BinOp::Mul, Some(0), _) | (BinOp::And | BinOp::Mul, _, Some(0)) => zero(),
+        (BinOp::And, Some(n), _) if n == mask => Some(b.clone()),
+        (BinOp::And, _, Some(n)) if n == mask => Some(a.clone()),
+        (BinOp::Or, Some(n), _) | (BinOp::Or, _, Some(n)) if n == mask => Some(n.to_string()),
+        (BinOp::Or | BinOp::Xor | BinOp::Add, Some(0), _) | (BinOp::Mul, Some(1), _) => {
+            Some(b.clone())
+        }
+        (BinOp::Or | BinOp::Xor | BinOp::Add | BinOp::Sub, _, Some(0))
+        | (BinOp::Mul, _, Some(1)) => Some(a.clone()),
+        (BinOp::LShift | BinOp::RShift, Some(0), _) => zero(),
+        (BinOp::LShift | BinOp::RShift, _, Some(0)) => Some(a.clone()),
+        (BinOp::LShift | BinOp::RShift, _, Some(n)) if n >= u64::from(width.0) => zero(),
+        (BinOp::And | BinOp::Or, _, _) if a == b => Some(a.clone()),
+        (BinOp::Xor | BinOp::Sub, _, _) if a == b => zero(),
+        _ => None,
+    }
+}
+
+/// Fold constants, collapse copies and simplify identities, then drop every
+/// wire that doesn't feed `outputs`. Wires in `keep` are left in place and
+/// nothing is folded through them, so they can still be overridden.
+fn optimize(circuit: &Circuit, outputs: &[&str], keep: &HashSet<&str>) -> Result<Circuit> {
+    if let Some(wire) = outputs.iter().find(|&&w| !circuit.parts.contains_key(w)) {
+        return Err(anyhow!("Unknown wire {wire:?}"));
+    }
+    let mut replaced: HashMap<&str, String> = HashMap::new();
+    let mut parts = HashMap::new();
+    for wire in topo_order(&circuit.parts)? {
+        let rename = |s: &String| replaced.get(s.as_str()).unwrap_or(s).clone();
+        let op = match &circuit.parts[wire].op {
+            Id(a) => Id(rename(a)),
+            Not(a) => Not(rename(a)),
+            Binary(op, a, b) => Binary(*op, rename(a), rename(b)),
+        };
+        let op = match simplify(&op, circuit.width) {
+            Some(operand) if !keep.contains(wire) => {
+                replaced.insert(wire, operand.clone());
+                if !outputs.contains(&wire) {
+                    continue;
+                }
+                Id(operand)
+            }
+            _ => op,
+        };
+        parts.insert(wire.to_owned(), Unresolved::new(op));
+    }
+
+    let live: HashSet<String> = outputs
+        .iter()
+        .flat_map(|output| fan_in(&parts, output))
+        .map(str::to_owned)
+        .collect();
+    parts.retain(|wire, _| live.contains(wire));
+    Ok(Circuit {
+        width: circuit.width,
+        parts,
+    })
+}
+
+/// Write the circuit back out as `<operation> -> <wire>` lines, with each
+/// wire after its dependencies.
+fn to_netlist(circuit: &Circuit) -> Result<String> {
+    let mut netlist = String::new();
+    if circuit.width != Width::default() {
+        writeln!(netlist, "width {}", circuit.width)?;
+    }
+    for wire in topo_order(&circuit.parts)? {
+        writeln!(netlist, "{} -> {wire}", circuit.parts[wire].op)?;
+    }
+    Ok(netlist)
+}
+
+/// A wire's value as a formula, where `Wire` is a free input or a wire
+/// defined by another formula.
+#[derive(Debug)]
+enum Expr {
+    Lit(u64),
+    Wire(String),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Format as an operand of another operator.
+    fn nested(&self) -> String {
+        match self {
+            Expr::Binary(op, _, _) if !matches!(op, BinOp::Nand | BinOp::Nor) => {
+                format!("({self})")
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Lit(n) => write!(f, "{n}"),
+            Expr::Wire(wire) => write!(f, "{wire}"),
+            Expr::Not(a) => write!(f, "~{}", a.nested()),
+            Expr::Binary(op, a, b) => {
+                let infix = format!("{} {} {}", a.nested(), op.symbol(), b.nested());
+                match op {
+                    BinOp::Nand | BinOp::Nor => write!(f, "~({infix})"),
+                    _ => write!(f, "{infix}"),
+                }
+            }
+        }
+    }
+}
+
+/// Express `output` as a simplified formula over the `free` wires, ignoring
+/// whatever drives them. Wires used more than once get their own formula,
+/// listed before the formulas that refer to them, to keep the size linear.
+fn expression(circuit: &Circuit, output: &str, free: &[&str]) -> Result<Vec<(String, Expr)>> {
+    if free.contains(&output) {
+        return Err(anyhow!("{output:?} is a free input"));
+    }
+    let mut parts = circuit.parts.clone();
+    for wire in free {
+        parts.insert(wire.to_string(), Unresolved::new(Id("0".to_owned())));
+    }
+    let circuit = Circuit {
+        width: circuit.width,
+        parts,
+    };
+    let circuit = optimize(&circuit, &[output], &free.iter().copied().collect())?;
+
+    // Count every operand, as a gate like `x MUL x` uses its input twice
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for uop in circuit.parts.values() {
+        for operand in uop.op.operands() {
+            *uses.entry(operand).or_default() += 1;
+        }
+    }
+    let shared = |wire: &str| free.contains(&wire) || uses.get(wire).map_or(0, |&n| n) > 1;
+    let mut formulas: Vec<(String, Expr)> = vec![];
+    let mut pending: HashMap<&str, Expr> = HashMap::new();
+    for wire in topo_order(&circuit.parts)? {
+        if free.contains(&wire) {
+            continue;
+        }
+        let mut operand = |s: &str| match s.parse::<u64>() {
+            Ok(n) => Expr::Lit(n),
+            Err(_) if shared(s) => Expr::Wire(s.to_owned()),
+            Err(_) => pending.remove(s).unwrap(),
+        };
+        let expr = match &circuit.parts[wire].op {
+            Id(a) => operand(a),
+            Not(a) => Expr::Not(Box::new(operand(a))),
+            Binary(op, a, b) => Expr::Binary(*op, Box::new(operand(a)), Box::new(operand(b))),
+        };
+        if wire == output || shared(wire) {
+            formulas.push((wire.to_owned(), expr));
+        } else {
+            pending.insert(wire, expr);
+        }
+    }
+    Ok(formulas)
+}
+
+/// The logic driving some output wires, ready to export. Undriven wires and
+/// free wires become inputs, and each gate comes after the gates it uses.
+struct Module {
+    width: Width,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    gates: Vec<(String, Operation)>,
+}
+
+impl Module {
+    fn new(circuit: &Circuit, outputs: &[&str], free: &[&str]) -> Result<Self> {
+        let mut parts = circuit.parts.clone();
+        parts.retain(|wire, _| !free.contains(&wire.as_str()));
+        if let Some(output) = outputs.iter().find(|&&w| !parts.contains_key(w)) {
+            return Err(anyhow!("Output {output:?} is not driven"));
+        }
+        let cone: HashSet<String> = outputs
+            .iter()
+            .flat_map(|output| fan_in(&parts, output))
+            .map(str::to_owned)
+            .collect();
+        parts.retain(|wire, _| cone.contains(wire));
+        let inputs: Vec<String> = cone
+            .into_iter()
+            .filter(|wire| !parts.contains_key(wire))
+            .sorted()
+            .collect();
+
+        // Inputs need placeholder drivers for the gates to be ordered
+        let mut placeheld = parts.clone();
+        for input in &inputs {
+            placeheld.insert(input.clone(), Unresolved::new(Id("0".to_owned())));
+        }
+        let gates = topo_order(&placeheld)?
+            .into_iter()
+            .filter_map(|wire| Some((wire.to_owned(), parts.get(wire)?.op.clone())))
+            .collect();
+        Ok(Module {
+            width: circuit.width,
+            inputs,
+            outputs: outputs.iter().map(|&w| w.to_owned()).collect(),
+            gates,
+        })
+    }
+
+    fn circuit(&self) -> Circuit {
+        Circuit {
+            width: self.width,
+            parts: self
+                .gates
+                .iter()
+                .map(|(wire, op)| (wire.clone(), Unresolved::new(op.clone())))
+                .collect(),
+        }
+    }
+
+    fn to_verilog(&self) -> String {
+        let bits = self.width.0;
+        let operand = |s: &str| match s.parse::<u64>() {
+            Ok(n) => format!("{bits}'d{n}"),
+            Err(_) => verilog_name(s),
+        };
+        let inputs = self.inputs.iter().map(|wire| ("input", wire));
+        let outputs = self.outputs.iter().map(|wire| ("output", wire));
+        let ports = inputs
+            .chain(outputs)
+            .map(|(dir, wire)| format!("    {dir} wire [{}:0] {}", bits - 1, verilog_name(wire)))
+            .join(",\n");
+        let mut verilog = format!("module circuit (\n{ports}\n);\n");
+        for (wire, _) in &self.gates {
+            if !self.outputs.contains(wire) {
+                writeln!(verilog, "    wire [{}:0] {};", bits - 1, verilog_name(wire)).unwrap();
+            }
+        }
+        for (wire, op) in &self.gates {
+            let expr = match op {
+                Id(a) => operand(a),
+                Not(a) => format!("~{}", operand(a)),
+                Binary(op, a, b) => {
+                    let infix = format!("{} {} {}", operand(a), op.symbol(), operand(b));
+                    match op {
+                        BinOp::Nand | BinOp::Nor => format!("~({infix})"),
+                        _ => infix,
+                    }
+                }
+            };
+            writeln!(verilog, "    assign {} = {expr};", verilog_name(wire)).unwrap();
+        }
+        verilog.push_str("endmodule\n");
+        verilog
+    }
+
+    fn to_blif(&self) -> String {
+        let mut blif = Blif {
+            width: self.width,
+            text: String::new(),
+            fresh: 0,
+        };
+        let ports = |wires: &[String]| {
+            let bits = wires.iter().flat_map(|wire| blif.signals(wire));
+            bits.collect::<Vec<String>>().join(" ")
+        };
+        let header = format!(
+            ".model circuit\n.inputs {}\n.outputs {}\n.names $false\n.names $true\n1\n",
+            ports(&self.inputs),
+            ports(&self.outputs)
+        );
+        blif.text.push_str(&header);
+        for (wire, op) in &self.gates {
+            blif.gate(wire, op);
+        }
+        blif.text.push_str(".end\n");
+        blif.text
+    }
+}
+
+const VERILOG_KEYWORDS: &[&str] = &[
+    "always",
+    "and",
+    "assign",
+    "automatic",
+    "begin",
+    "buf",
+    "bufif0",
+    "bufif1",
+    "case",
+    "casex",
+    "casez",
+    "cell",
+    "cmos",
+    "config",
+    "deassign",
+    "default",
+    "defparam",
+    "design",
+    "disable",
+    "edge",
+    "else",
+    "end",
+    "endcase",
+    "endconfig",
+    "endfunction",
+    "endgenerate",
+    "endmodule",
+    "endprimitive",
+    "endspecify",
+    "endtable",
+    "endtask",
+    "event",
+    "for",
+    "force",
+    "forever",
+    "fork",
+    "function",
+    "generate",
+    "genvar",
+    "highz0",
+    "highz1",
+    "if",
+    "ifnone",
+    "incdir",
+    "include",
+    "initial",
+    "inout",
+    "input",
+    "instance",
+    "integer",
+    "join",
+    "large",
+    "liblist",
+    "library",
+    "localparam",
+    "macromodule",
+    "medium",
+    "module",
+    "nand",
+    "negedge",
+    "nmos",
+    "nor",
+    "noshowcancelled",
+    "not",
+    "notif0",
+    "notif1",
+    "or",
+    "output",
+    "parameter",
+    "pmos",
+    "posedge",
+    "primitive",
+    "pull0",
+    "pull1",
+    "pulldown",
+    "pullup",
+    "pulsestyle_ondetect",
+    "pulsestyle_onevent",
+    "rcmos",
+    "real",
+    "realtime",
+    "reg",
+    "release",
+    "repeat",
+    "rnmos",
+    "rpmos",
+    "rtran",
+    "rtranif0",
+    "rtranif1",
+    "scalared",
+    "showcancelled",
+    "signed",
+    "small",
+    "specify",
+    "specparam",
+    "strong0",
+    "strong1",
+    "supply0",
+    "supply1",
+    "table",
+    "task",
+    "time",
+    "tran",
+    "tranif0",
+    "tranif1",
+    "tri",
+    "tri0",
+    "tri1",
+    "triand",
+    "trior",
+    "trireg",
+    "unsigned",
+    "use",
+    "uwire",
+    "vectored",
+    "wait",
+    "wand",
+    "weak0",
+    "weak1",
+    "while",
+    "wire",
+    "wor",
+    "xnor",
+    "xor",
+];
+
+/// A Verilog identifier for `wire`, escaped if it is a keyword or otherwise
+/// not a plain identifier.
+fn verilog_name(wire: &str) -> String {
+    let plain = wire.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && wire
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if plain && !VERILOG_KEYWORDS.contains(&wire) {
+        wire.to_owned()
+    } else {
+        format!("\\{wire} ")
+    }
+}
+
+const BUF: &[&str] = &["1 1"];
+const INV: &[&str] = &["0 1"];
+const AND: &[&str] = &["11 1"];
+const OR: &[&str] = &["1- 1", "-1 1"];
+const XOR: &[&str] = &["10 1", "01 1"];
+const NAND: &[&str] = &["0- 1", "-0 1"];
+const NOR: &[&str] = &["00 1"];
+const AND_NOT: &[&str] = &["10 1"];
+const XOR3: &[&str] = &["100 1", "010 1", "001 1", "111 1"];
+const MAJ3: &[&str] = &["11- 1", "1-1 1", "-11 1"];
+/// Selects the second input if the first is 0, else the third.
+const MUX: &[&str] = &["01- 1", "1-1 1"];
+
+/// Writes single-bit `.names` gates for each word-wide operation, where bit
+/// `i` of wire `w` is the signal `w[i]`.
+struct Blif {
+    width: Width,
+    text: String,
+    fresh: usize,
+}
+
+impl Blif {
+    fn names(&mut self, inputs: &[&str], output: &str, cover: &[&str]) {
+        let signals = inputs.iter().chain([&output]).join(" ");
+        writeln!(self.text, ".names {signals}").unwrap();
+        for row in cover {
+            writeln!(self.text, "{row}").unwrap();
+        }
+    }
+
+    fn fresh(&mut self) -> String {
+        self.fresh += 1;
+        format!("$t{}", self.fresh)
+    }
+
+    fn fresh_word(&mut self) -> Vec<String> {
+        (0..self.width.0).map(|_| self.fresh()).collect()
     }
 
-    let wire_a = *resolve_all(&parts).get("a").unwrap();
+    /// The signals for each bit of a wire or literal, lowest first.
+    fn signals(&self, operand: &str) -> Vec<String> {
+        let bits = 0..self.width.0;
+        match operand.parse::<u64>() {
+            Ok(n) => bits
+                .map(|i| if n >> i & 1 == 1 { "$true" } else { "$false" }.to_owned())
+                .collect(),
+            Err(_) => bits.map(|i| format!("{operand}[{i}]")).collect(),
+        }
+    }
+
+    fn bitwise(&mut self, a: &[String], b: &[String], out: &[String], cover: &[&str]) {
+        for i in 0..out.len() {
+            self.names(&[&a[i], &b[i]], &out[i], cover);
+        }
+    }
+
+    fn unary(&mut self, a: &[String], out: &[String], cover: &[&str]) {
+        for i in 0..out.len() {
+            self.names(&[&a[i]], &out[i], cover);
+        }
+    }
+
+    fn add(&mut self, a: &[String], b: &[String], carry: &str, out: &[String]) {
+        let mut carry = carry.to_owned();
+        for i in 0..out.len() {
+            self.names(&[&a[i], &b[i], &carry], &out[i], XOR3);
+            if i + 1 < out.len() {
+                let next = self.fresh();
+                self.names(&[&a[i], &b[i], &carry], &next, MAJ3);
+                carry = next;
+            }
+        }
+    }
+
+    /// Shift-and-add multiplication, keeping the low bits.
+    fn mul(&mut self, a: &[String], b: &[String], out: &[String]) {
+        let bits = out.len();
+        let mut sum = vec!["$false".to_owned(); bits];
+        for j in 0..bits {
+            let mut partial = vec!["$false".to_owned(); bits];
+            for i in j..bits {
+                partial[i] = self.fresh();
+                self.names(&[&a[i - j], &b[j]], &partial[i], AND);
+            }
+            let next = if j + 1 == bits {
+                out.to_vec()
+            } else {
+                self.fresh_word()
+            };
+            self.add(&sum, &partial, "$false", &next);
+            sum = next;
+        }
+    }
+
+    /// The bit that lands on bit `i` when shifting by `n`, if any.
+    fn shifted(op: BinOp, a: &[String], i: usize, n: usize) -> &str {
+        let from = match op {
+            BinOp::LShift => i.checked_sub(n),
+            _ => Some(i + n).filter(|&j| j < a.len()),
+        };
+        from.map_or("$false", |j| &a[j])
+    }
+
+    /// A barrel shifter, with one stage of multiplexers per bit of the shift
+    /// amount and zeros for amounts of the width or more.
+    fn shift(&mut self, op: BinOp, a: &[String], b: &[String], out: &[String]) {
+        let bits = out.len();
+        let stages = bits.trailing_zeros() as usize;
+        let mut word = a.to_vec();
+        for (k, select) in b.iter().enumerate().take(stages) {
+            let next = self.fresh_word();
+            for i in 0..bits {
+                let shifted = Self::shifted(op, &word, i, 1 << k);
+                self.names(&[select, &word[i], shifted], &next[i], MUX);
+            }
+            word = next;
+        }
+        let high: Vec<&str> = b[stages..].iter().map(String::as_str).collect();
+        let cover: Vec<String> = (0..high.len())
+            .map(|k| format!("{}1{} 1", "-".repeat(k), "-".repeat(high.len() - k - 1)))
+            .collect();
+        let overflow = self.fresh();
+        self.names(
+            &high,
+            &overflow,
+            &cover.iter().map(String::as_str).collect_vec(),
+        );
+        for i in 0..bits {
+            self.names(&[&word[i], &overflow], &out[i], AND_NOT);
+        }
+    }
+
+    fn gate(&mut self, wire: &str, op: &Operation) {
+        let out = self.signals(wire);
+        match op {
+            Id(a) => self.unary(&self.signals(a), &out, BUF),
+            Not(a) => self.unary(&self.signals(a), &out, INV),
+            Binary(op, a, b) => {
+                let (x, y) = (self.signals(a), self.signals(b));
+                match op {
+                    BinOp::And => self.bitwise(&x, &y, &out, AND),
+                    BinOp::Or => self.bitwise(&x, &y, &out, OR),
+                    BinOp::Xor => self.bitwise(&x, &y, &out, XOR),
+                    BinOp::Nand => self.bitwise(&x, &y, &out, NAND),
+                    BinOp::Nor => self.bitwise(&x, &y, &out, NOR),
+                    BinOp::Add => self.add(&x, &y, "$false", &out),
+                    BinOp::Sub => {
+                        let inverted = self.fresh_word();
+                        self.unary(&y, &inverted, INV);
+                        self.add(&x, &inverted, "$true", &out);
+                    }
+                    BinOp::Mul => self.mul(&x, &y, &out),
+                    BinOp::LShift | BinOp::RShift => match b.parse::<usize>() {
+                        // Shifting by a literal is just wiring
+                        Ok(n) => {
+                            for (i, bit) in out.iter().enumerate() {
+                                self.names(&[Self::shifted(*op, &x, i, n)], bit, BUF);
+                            }
+                        }
+                        Err(_) => self.shift(*op, &x, &y, &out),
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// A BLIF netlist read back for simulation, where each gate may only use
+/// signals defined before it.
+struct BlifSim {
+    gates: Vec<(Vec<String>, String, Vec<String>)>,
+}
+
+impl BlifSim {
+    fn parse(blif: &str) -> Result<Self> {
+        let mut gates: Vec<(Vec<String>, String, Vec<String>)> = vec![];
+        for line in blif.lines() {
+            if let Some(signals) = line.strip_prefix(".names ") {
+                let mut inputs: Vec<String> =
+                    signals.split_whitespace().map(str::to_owned).collect();
+                let output = inputs.pop().ok_or_else(|| anyhow!("Empty .names"))?;
+                gates.push((inputs, output, vec![]));
+            } else if !line.starts_with('.') {
+                let (_, _, rows) = gates
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("Cover row {line:?} outside of .names"))?;
+                rows.push(line.to_owned());
+            }
+        }
+        Ok(BlifSim { gates })
+    }
+
+    /// Evaluate every gate, given the input signals in `values`.
+    fn run(&self, values: &mut HashMap<String, bool>) -> Result<()> {
+        for (inputs, output, rows) in &self.gates {
+            let bits = inputs
+                .iter()
+                .map(|input| {
+                    values
+                        .get(input)
+                        .copied()
+                        .ok_or_else(|| anyhow!("{input} is used before it is defined"))
+                })
+                .collect::<Result<Vec<bool>>>()?;
+            let on = rows.iter().any(|row| {
+                let pattern = row.split_whitespace().next().filter(|_| !inputs.is_empty());
+                pattern
+                    .unwrap_or("")
+                    .chars()
+                    .zip(&bits)
+                    .all(|(c, &bit)| match c {
+                        '1' => bit,
+                        '0' => !bit,
+                        _ => true,
+                    })
+            });
+            values.insert(output.clone(), on);
+        }
+        Ok(())
+    }
+}
+
+/// Values worth feeding every gate at `width` bits: the ends of the range,
+/// bit patterns, and shift amounts on either side of the width. There are a
+/// prime number of them, which `check_export` relies on.
+fn edge_values(width: Width) -> [u64; 19] {
+    let mask = width.mask();
+    let bits = u64::from(width.0);
+    [
+        0,
+        1,
+        2,
+        3,
+        4,
+        5,
+        7,
+        8,
+        15 & mask,
+        16 & mask,
+        bits - 1,
+        bits,
+        bits + 1,
+        mask >> 1,
+        (mask >> 1) + 1,
+        0x5555_5555_5555_5555 & mask,
+        0xaaaa_aaaa_aaaa_aaaa & mask,
+        mask - 1,
+        mask,
+    ]
+}
+
+/// Compare the evaluator with a simulation of the module's BLIF netlist on
+/// `vectors` inputs made of edge values. Input `k` of vector `q * n + r`
+/// takes edge value `(r + q * (k + 1)) % n`, so any two inputs whose
+/// difference in `k` is not a multiple of `n` see every pair of edge values
+/// within `n * n` vectors.
+fn check_export(module: &Module, vectors: usize) -> Result<()> {
+    let inputs: Vec<&str> = module.inputs.iter().map(String::as_str).collect();
+    let program = Program::compile(&module.circuit(), &inputs)?;
+    let blif = Blif {
+        width: module.width,
+        text: String::new(),
+        fresh: 0,
+    };
+    let sim = BlifSim::parse(&module.to_blif())?;
+    let edges = edge_values(module.width);
+    let n = edges.len();
+    let mut results = vec![0; program.gates.len()];
+    for vector in 0..vectors {
+        let (q, r) = (vector / n, vector % n);
+        let values: Vec<u64> = (0..inputs.len())
+            .map(|k| edges[(r + q * (k + 1)) % n])
+            .collect();
+        program.run(&values, &mut results);
+        let mut bits = HashMap::from([("$false".to_owned(), false), ("$true".to_owned(), true)]);
+        for (input, value) in inputs.iter().zip(&values) {
+            for (i, signal) in blif.signals(input).into_iter().enumerate() {
+                bits.insert(signal, value >> i & 1 == 1);
+            }
+        }
+        sim.run(&mut bits)?;
+        for output in &module.outputs {
+            let expected = results[program.slot(output)?];
+            let simulated = blif
+                .signals(output)
+                .iter()
+                .enumerate()
+                .fold(0, |value, (i, signal)| value | u64::from(bits[signal]) << i);
+            if simulated != expected {
+                let mut assignment = inputs
+                    .iter()
+                    .zip(&values)
+                    .map(|(w, v)| format!("{w} = {v}"));
+                return Err(anyhow!(
+                    "{output} is {expected} but the BLIF gives {simulated} for {}",
+                    assignment.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Render the circuit (or just `wires`) as a Graphviz DOT graph, with wires
+/// as ellipses and gates as boxes. `Id` gates from another wire are drawn
+/// as plain edges, and literal operands as plain text nodes.
+fn to_dot(
+    parts: &HashMap<String, Unresolved>,
+    wires: Option<&HashSet<&str>>,
+    values: Option<&HashMap<String, u64>>,
+) -> String {
+    let mut dot = String::from("digraph circuit {\n    rankdir=LR;\n");
+    let shown = |wire: &str| wires.map_or(true, |wires| wires.contains(wire));
+    for (dst, uop) in parts.iter().sorted_by_key(|(dst, _)| dst.as_str()) {
+        if !shown(dst) {
+            continue;
+        }
+        let label = match values.and_then(|values| values.get(dst)) {
+            Some(value) => format!("{dst}\\n{value}"),
+            None => dst.to_owned(),
+        };
+        writeln!(dot, "    \"{dst}\" [label=\"{label}\"];").unwrap();
+        let operands = uop.op.operands();
+        let target = match (&uop.op, uop.deps.len()) {
+            (Id(_), 1) => dst.to_owned(),
+            _ => {
+                let gate = format!("{dst}:gate");
+                writeln!(
+                    dot,
+                    "    \"{gate}\" [shape=box, label=\"{}\"];",
+                    uop.op.gate_label()
+                )
+                .unwrap();
+                writeln!(dot, "    \"{gate}\" -> \"{dst}\";").unwrap();
+                gate
+            }
+        };
+        for (i, operand) in operands.into_iter().enumerate() {
+            if uop.deps.contains(operand) {
+                writeln!(dot, "    \"{operand}\" -> \"{target}\";").unwrap();
+            } else {
+                let literal = format!("{dst}:literal{i}");
+                writeln!(
+                    dot,
+                    "    \"{literal}\" [shape=plaintext, label=\"{operand}\"];"
+                )
+                .unwrap();
+                writeln!(dot, "    \"{literal}\" -> \"{target}\";").unwrap();
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn main() -> Result<()> {
+    let mut dot = false;
+    let mut with_values = false;
+    let mut cone = None;
+    let mut repl_path = None;
+    let mut what_if = None;
+    let mut optimize_outputs = None;
+    let mut keep = String::new();
+    let mut expr_output = None;
+    let mut free = String::new();
+    let mut goal = None;
+    let mut limit = 10;
+    let mut sensitivity = None;
+    let mut timing_output = None;
+    let mut delays_path = None;
+    let mut export = None;
+    let mut outputs = None;
+    let mut check_vectors = None;
+    let mut fault = None;
+    let mut influence = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dot" => dot = true,
+            "--values" => with_values = true,
+            "--cone" => cone = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a wire"))?),
+            "--repl" => repl_path = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a file"))?),
+            "--what-if" => {
+                let mut value = || args.next().ok_or_else(|| anyhow!("{arg} needs 3 values"));
+                let wire = value()?;
+                let (from, to) = value()?
+                    .split_once("..")
+                    .map(|(from, to)| Ok::<(u64, u64), ParseIntError>((from.parse()?, to.parse()?)))
+                    .ok_or_else(|| anyhow!("{arg} needs a <from>..<to> range"))??;
+                what_if = Some((wire, from..=to, value()?));
+            }
+            "--optimize" => {
+                optimize_outputs = Some(args.next().ok_or_else(|| anyhow!("{arg} needs wires"))?)
+            }
+            "--keep" => keep = args.next().ok_or_else(|| anyhow!("{arg} needs wires"))?,
+            "--expr" => {
+                expr_output = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a wire"))?)
+            }
+            "--free" => free = args.next().ok_or_else(|| anyhow!("{arg} needs wires"))?,
+            "--solve" => {
+                let goal_arg = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{arg} needs <wire>=<value>"))?;
+                let (wire, value) = goal_arg
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("{arg} needs <wire>=<value>"))?;
+                goal = Some((wire.to_owned(), value.parse::<u64>()?));
+            }
+            "--limit" => {
+                let n: NonZeroUsize = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{arg} needs a count"))?
+                    .parse()
+                    .map_err(|e| anyhow!("{arg} needs a positive count ({e})"))?;
+                limit = n.get();
+            }
+            "--sensitivity" => {
+                let mut wire = || args.next().ok_or_else(|| anyhow!("{arg} needs 2 wires"));
+                sensitivity = Some((wire()?, wire()?));
+            }
+            "--timing" => {
+                timing_output = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a wire"))?)
+            }
+            "--delays" => {
+                delays_path = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a file"))?)
+            }
+            "--verilog" | "--blif" => export = Some(arg),
+            "--outputs" => outputs = Some(args.next().ok_or_else(|| anyhow!("{arg} needs wires"))?),
+            "--check-export" => {
+                check_vectors = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("{arg} needs a count"))?
+                        .parse()?,
+                )
+            }
+            "--fault" => {
+                fault = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("{arg} needs a fault"))?
+                        .parse::<Fault>()?,
+                )
+            }
+            "--influence" => {
+                influence = Some(args.next().ok_or_else(|| anyhow!("{arg} needs a wire"))?)
+            }
+            _ => return Err(anyhow!("Unknown argument {arg:?}")),
+        }
+    }
+
+    if let Some(path) = repl_path {
+        return repl(&path);
+    }
+
+    let circuit = parse_circuit(io::stdin().lines().map(Result::unwrap))?;
+
+    if dot {
+        let wires = cone.as_deref().map(|wire| fan_in(&circuit.parts, wire));
+        let values = if with_values {
+            Some(resolve_all(&circuit.parts, circuit.width)?)
+        } else {
+            None
+        };
+        print!(
+            "{}",
+            to_dot(&circuit.parts, wires.as_ref(), values.as_ref())
+        );
+        return Ok(());
+    }
+
+    if let Some(outputs) = optimize_outputs {
+        let outputs: Vec<&str> = outputs.split(',').collect();
+        let keep: HashSet<&str> = keep.split(',').filter(|w| !w.is_empty()).collect();
+        let optimized = optimize(&circuit, &outputs, &keep)?;
+        println!(
+            "# {} gates before, {} after",
+            circuit.parts.len(),
+            optimized.parts.len()
+        );
+        print!("{}", to_netlist(&optimized)?);
+        return Ok(());
+    }
+
+    if let Some(output) = expr_output {
+        let free: Vec<&str> = free.split(',').filter(|w| !w.is_empty()).collect();
+        for (wire, expr) in expression(&circuit, &output, &free)? {
+            println!("{wire} = {expr}");
+        }
+        return Ok(());
+    }
+
+    if export.is_some() || check_vectors.is_some() {
+        // Export the wires nothing else uses unless told otherwise
+        let users = users(&circuit.parts);
+        let outputs: Vec<&str> = match &outputs {
+            Some(outputs) => outputs.split(',').collect(),
+            None => circuit
+                .parts
+                .keys()
+                .map(String::as_str)
+                .filter(|wire| !users.contains_key(wire))
+                .sorted()
+                .collect(),
+        };
+        let free: Vec<&str> = free.split(',').filter(|w| !w.is_empty()).collect();
+        let module = Module::new(&circuit, &outputs, &free)?;
+        match export.as_deref() {
+            Some("--verilog") => print!("{}", module.to_verilog()),
+            Some(_) => print!("{}", module.to_blif()),
+            None => {}
+        }
+        if let Some(vectors) = check_vectors {
+            check_export(&module, vectors)?;
+            println!("BLIF matches the evaluator on {vectors} inputs");
+        }
+        return Ok(());
+    }
+
+    if let Some(output) = timing_output {
+        let delays = match delays_path {
+            Some(path) => parse_delays(&std::fs::read_to_string(path)?)?,
+            None => Delays::default(),
+        };
+        print!("{}", timing_report(&circuit, &output, &delays)?);
+        return Ok(());
+    }
+
+    if let Some((input, output)) = sensitivity {
+        print!("{}", Sensitivity::new(&circuit, &input, &output)?);
+        return Ok(());
+    }
+
+    if let Some((wire, target)) = goal {
+        let free: Vec<&str> = free.split(',').filter(|w| !w.is_empty()).collect();
+        let solutions = solve(&circuit, &wire, target, &free, limit)?;
+        if solutions.is_empty() {
+            println!("No solution for {wire} = {target}");
+        }
+        for solution in &solutions {
+            let assignment = free.iter().zip(solution);
+            println!(
+                "{}",
+                assignment.map(|(w, v)| format!("{w} = {v}")).join(", ")
+            );
+        }
+        if solutions.len() == limit {
+            println!("Stopped after {limit} solutions");
+        }
+        return Ok(());
+    }
+
+    if let Some((wire, values, output)) = what_if {
+        if *values.end() > circuit.width.mask() {
+            return Err(anyhow!(
+                "{} does not fit in {} bits",
+                values.end(),
+                circuit.width
+            ));
+        }
+        let program = Program::compile(&circuit, &[&wire])?;
+        let slot = program.slot(&output)?;
+        let mut results = vec![0; program.gates.len()];
+        for value in values {
+            program.run(&[value], &mut results);
+            println!("{wire} = {value}: {output} = {}", results[slot]);
+        }
+        return Ok(());
+    }
+
+    if let Some(output) = influence {
+        print!("{}", influence_report(&circuit, &output)?);
+        return Ok(());
+    }
+
+    let mut evaluator = Evaluator::new(circuit.parts, circuit.width)?;
+    if let Some(fault) = fault {
+        print!("{}", fault_report(&mut evaluator, &fault)?);
+        return Ok(());
+    }
+
+    let wire_a = evaluator.get("a").unwrap();
     println!("Part 1: {}", wire_a);
 
-    parts.insert(
-        "b".to_owned(),
+    evaluator.set(
+        "b",
         Unresolved {
             op: Id(wire_a.to_string()),
             deps: HashSet::new(),
         },
-    );
-    println!("Part 2: {}", resolve_all(&parts).get("a").unwrap());
+    )?;
+    println!("Part 2: {}", evaluator.get("a").unwrap());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One gate of every kind, shifting by both a literal and a wire.
+    const EVERY_GATE: &str = "
+        x -> id
+        NOT x -> not
+        x AND y -> and
+        x OR y -> or
+        x XOR y -> xor
+        x NAND y -> nand
+        x NOR y -> nor
+        x ADD y -> add
+        x SUB y -> sub
+        x MUL y -> mul
+        x LSHIFT 3 -> shl
+        x RSHIFT 3 -> shr
+        x LSHIFT y -> shlw
+        x RSHIFT y -> shrw
+    ";
+
+    fn module(width: u32, text: &str, outputs: &[&str]) -> Module {
+        let text = format!("width {width}\n{text}");
+        let circuit = parse_circuit(text.lines().map(str::to_owned)).unwrap();
+        Module::new(&circuit, outputs, &[]).unwrap()
+    }
+
+    #[test]
+    fn blif_matches_evaluator() {
+        let outputs = [
+            "id", "not", "and", "or", "xor", "nand", "nor", "add", "sub", "mul", "shl", "shr",
+            "shlw", "shrw",
+        ];
+        for width in [8, 16, 32, 64] {
+            let module = module(width, EVERY_GATE, &outputs);
+            // Enough vectors for x and y to meet every pair of edge values
+            let n = edge_values(module.width).len();
+            if let Err(e) = check_export(&module, n * n) {
+                panic!("{width} bits: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn wire_named_width() {
+        let text = "width 8\n1 -> b\nb -> width\nwidth OR 2 -> a";
+        let circuit = parse_circuit(text.lines().map(str::to_owned)).unwrap();
+        assert_eq!(circuit.width.0, 8);
+        assert_eq!(resolve_all(&circuit.parts, circuit.width).unwrap()["a"], 3);
+    }
+
+    #[test]
+    fn expression_shares_wires_used_twice_by_one_gate() {
+        let text = "b ADD 1 -> x\nx MUL x -> a\n5 -> b";
+        let circuit = parse_circuit(text.lines().map(str::to_owned)).unwrap();
+        let formulas: Vec<String> = expression(&circuit, "a", &["b"])
+            .unwrap()
+            .into_iter()
+            .map(|(wire, expr)| format!("{wire} = {expr}"))
+            .collect();
+        assert_eq!(formulas, ["x = b + 1", "a = x * x"]);
+    }
+
+    #[test]
+    fn verilog_golden() {
+        let text = "
+            x AND 3 -> a
+            NOT a -> b
+            x NAND y -> c
+            y LSHIFT 2 -> d
+            c RSHIFT d -> out
+            x OR y -> reg
+        ";
+        let verilog = module(8, text, &["b", "out", "reg"]).to_verilog();
+        let expected = [
+            "module circuit (",
+            "    input wire [7:0] x,",
+            "    input wire [7:0] y,",
+            "    output wire [7:0] b,",
+            "    output wire [7:0] out,",
+            "    output wire [7:0] \\reg ",
+            ");",
+            "    wire [7:0] d;",
+            "    wire [7:0] c;",
+            "    wire [7:0] a;",
+            "    assign d = y << 8'd2;",
+            "    assign \\reg  = x | y;",
+            "    assign c = ~(x & y);",
+            "    assign out = c >> d;",
+            "    assign a = x & 8'd3;",
+            "    assign b = ~a;",
+            "endmodule",
+        ];
+        assert_eq!(verilog.lines().collect_vec(), expected);
+    }
 }